@@ -0,0 +1,612 @@
+use clap::{Parser, Subcommand, ValueEnum};
+use files::bulk::Selection;
+use files::config::{Config, Vaults};
+use files::db::{Database, FileEntry, FileMetadata, SortOrder};
+use files::error::Error;
+use files::import;
+use files::rules;
+use files::transfer::ImportMode;
+use files::vault::{self, Problem};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+/// Command-line interface to the files archive. It works on the same vault
+/// and database as the desktop app.
+#[derive(Parser, Debug)]
+#[command(name = "files-cli", version, about)]
+struct Cli {
+    /// Print results as JSON
+    #[arg(long, global = true)]
+    json: bool,
+
+    /// Vault to work on instead of the one open in the app
+    #[arg(long, global = true)]
+    vault: Option<String>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Import files into the vault, `-` reads a file from stdin
+    Import {
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+        /// Tag of the files, can be repeated
+        #[arg(short, long = "tag")]
+        tags: Vec<String>,
+        /// Category value as `category=value`, can be repeated, also for
+        /// several values of a category
+        #[arg(short, long = "category", value_parser = parse_category)]
+        categories: Vec<(String, String)>,
+        /// Date of the documents as YYYY-MM-DD
+        #[arg(short, long)]
+        date: Option<String>,
+        /// File name for a file read from stdin
+        #[arg(short, long)]
+        name: Option<String>,
+        /// Import files even if their content is already in the vault
+        #[arg(long)]
+        allow_duplicates: bool,
+        /// How files get into the vault, instead of the mode of the config
+        #[arg(short, long, value_enum)]
+        mode: Option<Mode>,
+        /// Import the files in subfolders of folders as well
+        #[arg(short, long)]
+        recursive: bool,
+        /// Only import files of folders matching the pattern, can be repeated
+        #[arg(long)]
+        include: Vec<String>,
+        /// Skip files and subfolders matching the pattern, can be repeated
+        #[arg(long)]
+        exclude: Vec<String>,
+    },
+    /// Search files, e.g. `tag:taxes AND (Year=2023 OR Year=2024)`
+    Search {
+        #[arg(required = true)]
+        query: Vec<String>,
+        #[arg(short, long, value_enum, default_value_t = Sort::Name)]
+        sort: Sort,
+    },
+    /// Add tags to a file
+    Tag {
+        /// Folder of the file in the vault
+        file: String,
+        #[arg(required = true)]
+        tags: Vec<String>,
+    },
+    /// Remove tags from a file
+    Untag {
+        /// Folder of the file in the vault
+        file: String,
+        #[arg(required = true)]
+        tags: Vec<String>,
+    },
+    /// List the tags with their aliases
+    Tags,
+    /// Add another name for a tag
+    Alias { alias: String, tag: String },
+    /// Remove an alias
+    Unalias { alias: String },
+    /// Fold a tag into another one, it becomes an alias of the other tag
+    Merge { from: String, into: String },
+    /// Delete files from the vault
+    Rm {
+        /// Folders of the files in the vault
+        #[arg(required = true)]
+        files: Vec<String>,
+    },
+    /// List all files
+    Ls {
+        #[arg(short, long, value_enum, default_value_t = Sort::Name)]
+        sort: Sort,
+    },
+    /// List files with the same content
+    Duplicates,
+    /// Compare the database with the files in the vault
+    Verify {
+        /// Compare the content with the hashes stored on import
+        #[arg(long)]
+        hashes: bool,
+        /// Remove entries of missing files, register orphans, store changed
+        /// hashes and delete dangling assignments
+        #[arg(long)]
+        repair: bool,
+    },
+    /// Show the tags and values the rules would give a file
+    Rules { file: PathBuf },
+    /// Print the configuration
+    Config,
+    /// List the vaults, the one open in the app is marked with `*`
+    Vaults,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum Sort {
+    Name,
+    Date,
+    DateDescending,
+    Imported,
+}
+
+impl From<Sort> for SortOrder {
+    fn from(sort: Sort) -> Self {
+        match sort {
+            Sort::Name => SortOrder::Name,
+            Sort::Date => SortOrder::Date,
+            Sort::DateDescending => SortOrder::DateDescending,
+            Sort::Imported => SortOrder::Imported,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum Mode {
+    Copy,
+    Move,
+    Hardlink,
+    Reflink,
+}
+
+impl From<Mode> for ImportMode {
+    fn from(mode: Mode) -> Self {
+        match mode {
+            Mode::Copy => ImportMode::Copy,
+            Mode::Move => ImportMode::Move,
+            Mode::Hardlink => ImportMode::Hardlink,
+            Mode::Reflink => ImportMode::Reflink,
+        }
+    }
+}
+
+fn parse_category(arg: &str) -> Result<(String, String), String> {
+    match arg.split_once('=') {
+        Some((category, value)) if !category.is_empty() && !value.is_empty() => {
+            Ok((category.to_string(), value.to_string()))
+        }
+        _ => Err(format!("expected `category=value`, found `{arg}`")),
+    }
+}
+
+fn load_config(vault: Option<&str>) -> Result<Config, Error> {
+    let vaults = Vaults::load()?;
+    match vault {
+        Some(name) => vaults.get(name).cloned(),
+        None => vaults.current().cloned(),
+    }
+}
+
+fn open(vault: Option<&str>) -> Result<(Config, Database), Error> {
+    let mut config = load_config(vault)?;
+    let db = vault::open(&mut config)?;
+    Ok((config, db))
+}
+
+fn print_files(files: &[FileEntry], json: bool) -> Result<(), Error> {
+    if json {
+        let json =
+            serde_json::to_string_pretty(files).map_err(|err| Error::Internal(err.to_string()))?;
+        println!("{json}");
+        return Ok(());
+    }
+    for file in files {
+        let mut categories: Vec<String> = file
+            .categories
+            .iter()
+            .flat_map(|(category, values)| {
+                values
+                    .iter()
+                    .map(move |value| format!("{category}={value}"))
+            })
+            .collect();
+        categories.sort();
+        println!(
+            "{}\t{}\t{}\t{}",
+            file.relative_location().display(),
+            file.date.as_deref().unwrap_or("-"),
+            file.tags.join(","),
+            categories.join(",")
+        );
+    }
+    Ok(())
+}
+
+/// Imports a file read from stdin, by storing it in a temporary folder first.
+fn import_stdin(
+    config: &Config,
+    db: &Database,
+    name: &str,
+    metadata: &FileMetadata,
+    allow_duplicate: bool,
+) -> Result<String, Error> {
+    let mut content = Vec::new();
+    io::stdin()
+        .read_to_end(&mut content)
+        .map_err(|err| Error::from(err).context("Reading stdin failed"))?;
+
+    let dir = std::env::temp_dir().join(format!("files-cli-{}", std::process::id()));
+    fs::create_dir_all(&dir)
+        .map_err(|err| Error::from(err).context("Creating temporary folder failed"))?;
+    let path = dir.join(name);
+    // The temporary file is not needed afterwards
+    let mut config = config.clone();
+    config.import_mode = ImportMode::Move;
+    let result = fs::write(&path, content)
+        .map_err(|err| Error::from(err).context("Writing temporary file failed"))
+        .and_then(|_| import::import_file(db, &config, &path, metadata, allow_duplicate));
+    let _ = fs::remove_dir_all(&dir);
+    result
+}
+
+fn describe(problem: &Problem) -> String {
+    match problem {
+        Problem::Missing { location, .. } => format!("missing: {}", location.display()),
+        Problem::Orphan { location } => format!("orphan: {}", location.display()),
+        Problem::HashMismatch { path, .. } => format!("changed: {path}"),
+        Problem::Dangling { tags, values } => {
+            format!("dangling: {tags} tag and {values} value assignments")
+        }
+    }
+}
+
+fn run(cli: Cli) -> Result<(), Error> {
+    match cli.command {
+        Command::Import {
+            paths,
+            tags,
+            categories,
+            date,
+            name,
+            allow_duplicates,
+            mode,
+            recursive,
+            include,
+            exclude,
+        } => {
+            let (mut config, db) = open(cli.vault.as_deref())?;
+            // Duplicates are only found among hashed files
+            vault::hash_missing(&db, &config.folder())?;
+            if let Some(mode) = mode {
+                config.import_mode = mode.into();
+            }
+            let mut values: HashMap<String, Vec<String>> = HashMap::new();
+            for (category, value) in categories {
+                values.entry(category).or_default().push(value);
+            }
+            let metadata = FileMetadata {
+                tags,
+                categories: values,
+                date: import::document_date(&config, date)?,
+            };
+            let mut files = Vec::new();
+            for path in paths {
+                if path.is_dir() {
+                    let selection = Selection {
+                        folder: path,
+                        recursive,
+                        include: include.clone(),
+                        exclude: exclude.clone(),
+                    };
+                    files.extend(selection.files(&config.folder())?);
+                } else {
+                    files.push(path);
+                }
+            }
+            for path in files {
+                let stored = if path == Path::new("-") {
+                    let name = match name {
+                        Some(ref name) => name.clone(),
+                        None => {
+                            return Err(Error::Invalid(
+                                "Importing from stdin requires --name".to_string(),
+                            ))
+                        }
+                    };
+                    import_stdin(&config, &db, &name, &metadata, allow_duplicates)?
+                } else {
+                    match import::import_file(&db, &config, &path, &metadata, allow_duplicates) {
+                        Ok(stored) => stored,
+                        Err(Error::Duplicate(location)) => {
+                            eprintln!(
+                                "skipping {}: already imported as {location}",
+                                path.display()
+                            );
+                            continue;
+                        }
+                        Err(err) => return Err(err.context(&path.display().to_string())),
+                    }
+                };
+                let entry = db.get_file(&stored)?;
+                println!("{}", entry.relative_location().display());
+            }
+            Ok(())
+        }
+        Command::Search { query, sort } => {
+            let (_, db) = open(cli.vault.as_deref())?;
+            let files = db.query(&query.join(" "), sort.into())?;
+            print_files(&files, cli.json)
+        }
+        Command::Ls { sort } => {
+            let (_, db) = open(cli.vault.as_deref())?;
+            let files = db.query("", sort.into())?;
+            print_files(&files, cli.json)
+        }
+        Command::Tag { file, tags } => {
+            let (_, db) = open(cli.vault.as_deref())?;
+            let entry = db.get_file(&file)?;
+            let mut new_tags = entry.tags.clone();
+            for tag in tags {
+                if !new_tags.contains(&tag) {
+                    new_tags.push(tag);
+                }
+            }
+            db.update_file_metadata(&file, &new_tags, &entry.categories)
+        }
+        Command::Untag { file, tags } => {
+            let (_, db) = open(cli.vault.as_deref())?;
+            let entry = db.get_file(&file)?;
+            let tags = tags
+                .iter()
+                .map(|tag| db.resolve_tag(tag))
+                .collect::<Result<Vec<String>, Error>>()?;
+            let new_tags: Vec<String> = entry
+                .tags
+                .into_iter()
+                .filter(|tag| !tags.contains(tag))
+                .collect();
+            db.update_file_metadata(&file, &new_tags, &entry.categories)
+        }
+        Command::Tags => {
+            let (config, _) = open(cli.vault.as_deref())?;
+            if cli.json {
+                let json = serde_json::to_string_pretty(&serde_json::json!({
+                    "tags": config.tags,
+                    "aliases": config.aliases,
+                }))
+                .map_err(|err| Error::Internal(err.to_string()))?;
+                println!("{json}");
+            } else {
+                for tag in config.tags.iter() {
+                    let mut aliases: Vec<&String> = config
+                        .aliases
+                        .iter()
+                        .filter(|(_, target)| *target == tag)
+                        .map(|(alias, _)| alias)
+                        .collect();
+                    aliases.sort();
+                    let aliases: Vec<&str> = aliases.iter().map(|a| a.as_str()).collect();
+                    println!("{tag}\t{}", aliases.join(","));
+                }
+            }
+            Ok(())
+        }
+        Command::Alias { alias, tag } => {
+            let (_, db) = open(cli.vault.as_deref())?;
+            db.add_alias(&alias, &tag)
+        }
+        Command::Unalias { alias } => {
+            let (_, db) = open(cli.vault.as_deref())?;
+            db.remove_alias(&alias)
+        }
+        Command::Merge { from, into } => {
+            let (_, db) = open(cli.vault.as_deref())?;
+            db.merge_tags(&from, &into)
+        }
+        Command::Rm { files } => {
+            let (config, db) = open(cli.vault.as_deref())?;
+            for file in files {
+                db.delete_file(&config.folder(), &file)?;
+            }
+            Ok(())
+        }
+        Command::Duplicates => {
+            let (config, db) = open(cli.vault.as_deref())?;
+            let groups = vault::find_duplicates(&db, &config.folder())?;
+            if cli.json {
+                let json = serde_json::to_string_pretty(&groups)
+                    .map_err(|err| Error::Internal(err.to_string()))?;
+                println!("{json}");
+                return Ok(());
+            }
+            for (i, group) in groups.iter().enumerate() {
+                if i > 0 {
+                    println!();
+                }
+                print_files(group, false)?;
+            }
+            Ok(())
+        }
+        Command::Verify { hashes, repair } => {
+            let (config, db) = open(cli.vault.as_deref())?;
+            let vault = config.folder();
+            let report = vault::verify(&db, &vault, hashes)?;
+            if cli.json {
+                let json = serde_json::to_string_pretty(&report)
+                    .map_err(|err| Error::Internal(err.to_string()))?;
+                println!("{json}");
+            } else {
+                for problem in &report.problems {
+                    println!("{}", describe(problem));
+                }
+                eprintln!(
+                    "{} files checked, {} problems",
+                    report.files,
+                    report.problems.len()
+                );
+            }
+            if repair {
+                for problem in &report.problems {
+                    match vault::repair(&db, &vault, problem) {
+                        Ok(()) => eprintln!("repaired: {}", describe(problem)),
+                        Err(err) => eprintln!("{err}"),
+                    }
+                }
+            }
+            Ok(())
+        }
+        Command::Rules { file } => {
+            let config = load_config(cli.vault.as_deref())?;
+            let matched = rules::preview(&config.rules, &file)?;
+            if cli.json {
+                let json = serde_json::to_string_pretty(&matched)
+                    .map_err(|err| Error::Internal(err.to_string()))?;
+                println!("{json}");
+            } else {
+                let mut categories: Vec<String> = matched
+                    .categories
+                    .iter()
+                    .flat_map(|(category, values)| {
+                        values
+                            .iter()
+                            .map(move |value| format!("{category}={value}"))
+                    })
+                    .collect();
+                categories.sort();
+                println!("rules\t{}", matched.rules.join(","));
+                println!("tags\t{}", matched.tags.join(","));
+                println!("categories\t{}", categories.join(","));
+            }
+            Ok(())
+        }
+        Command::Config => {
+            // With the tags and categories stored in the vault
+            let (config, _) = open(cli.vault.as_deref())?;
+            let json = serde_json::to_string_pretty(&config)
+                .map_err(|err| Error::Internal(err.to_string()))?;
+            println!("{json}");
+            Ok(())
+        }
+        Command::Vaults => {
+            let vaults = Vaults::load()?.list();
+            if cli.json {
+                let json = serde_json::to_string_pretty(&vaults)
+                    .map_err(|err| Error::Internal(err.to_string()))?;
+                println!("{json}");
+            } else {
+                for vault in vaults {
+                    let marker = if vault.current { "*" } else { " " };
+                    println!("{marker} {}\t{}", vault.name, vault.folder);
+                }
+            }
+            Ok(())
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    env_logger::init();
+    match run(Cli::parse()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(Error::NotConfigured) => {
+            eprintln!("error: no vault is configured, set it up in the app first");
+            ExitCode::FAILURE
+        }
+        Err(err) => {
+            eprintln!("error: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn cli_definition() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn parse_import() {
+        let cli = Cli::try_parse_from([
+            "files-cli",
+            "import",
+            "scan.pdf",
+            "-t",
+            "taxes",
+            "--category",
+            "Year=2024",
+            "--date",
+            "2024-03-01",
+        ])
+        .expect("Parsing failed");
+        match cli.command {
+            Command::Import {
+                paths,
+                tags,
+                categories,
+                date,
+                name,
+                allow_duplicates,
+                mode,
+                recursive,
+                ..
+            } => {
+                assert!(!allow_duplicates);
+                assert!(!recursive);
+                assert_eq!(None, mode);
+                assert_eq!(vec![PathBuf::from("scan.pdf")], paths);
+                assert_eq!(vec!["taxes".to_string()], tags);
+                assert_eq!(vec![("Year".to_string(), "2024".to_string())], categories);
+                assert_eq!(Some("2024-03-01".to_string()), date);
+                assert_eq!(None, name);
+            }
+            command => panic!("Unexpected command {command:?}"),
+        }
+
+        assert!(Cli::try_parse_from(["files-cli", "import", "a.pdf", "-c", "Year"]).is_err());
+    }
+
+    #[test]
+    fn parse_search() {
+        let cli = Cli::try_parse_from([
+            "files-cli",
+            "--json",
+            "search",
+            "tag:taxes",
+            "NOT",
+            "tag:draft",
+            "--sort",
+            "date-descending",
+        ])
+        .expect("Parsing failed");
+        assert!(cli.json);
+        match cli.command {
+            Command::Search { query, sort } => {
+                assert_eq!("tag:taxes NOT tag:draft", query.join(" "));
+                assert_eq!(Sort::DateDescending, sort);
+            }
+            command => panic!("Unexpected command {command:?}"),
+        }
+    }
+
+    #[test]
+    fn parse_vault() {
+        let cli = Cli::try_parse_from(["files-cli", "ls", "--vault", "personal"])
+            .expect("Parsing failed");
+        assert_eq!(Some("personal".to_string()), cli.vault);
+        let cli = Cli::try_parse_from(["files-cli", "vaults"]).expect("Parsing failed");
+        assert_eq!(None, cli.vault);
+        assert!(matches!(cli.command, Command::Vaults));
+    }
+
+    #[test]
+    fn parse_merge() {
+        let cli = Cli::try_parse_from(["files-cli", "merge", "ins", "insurance"])
+            .expect("Parsing failed");
+        match cli.command {
+            Command::Merge { from, into } => {
+                assert_eq!("ins", from);
+                assert_eq!("insurance", into);
+            }
+            command => panic!("Unexpected command {command:?}"),
+        }
+        assert!(Cli::try_parse_from(["files-cli", "alias", "ins"]).is_err());
+    }
+}
@@ -0,0 +1,417 @@
+use crate::config::Config;
+use crate::db::{Database, FileMetadata};
+use crate::error::Error;
+use crate::import;
+use glob::Pattern;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+/// Which files of a folder are imported.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Selection {
+    pub folder: PathBuf,
+    /// Whether files in subfolders are imported as well
+    #[serde(default)]
+    pub recursive: bool,
+    /// Only files matching one of these patterns are imported, all files if
+    /// empty. Patterns without `/` match the file name, others the path
+    /// relative to `folder`.
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// Files and folders matching one of these patterns are skipped
+    #[serde(default)]
+    pub exclude: Vec<String>,
+}
+
+/// Reported while a bulk import runs. `done` counts the processed files.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum Progress {
+    Started {
+        total: usize,
+    },
+    Imported {
+        path: PathBuf,
+        stored: String,
+        done: usize,
+        total: usize,
+    },
+    /// The content is already stored in the vault as `existing`
+    Duplicate {
+        path: PathBuf,
+        existing: String,
+        done: usize,
+        total: usize,
+    },
+    Failed {
+        path: PathBuf,
+        error: Error,
+        done: usize,
+        total: usize,
+    },
+    Finished {
+        imported: usize,
+        duplicates: usize,
+        failed: usize,
+        cancelled: bool,
+    },
+}
+
+fn patterns(patterns: &[String]) -> Result<Vec<Pattern>, Error> {
+    patterns
+        .iter()
+        .map(|pattern| {
+            Pattern::new(pattern)
+                .map_err(|err| Error::Invalid(format!("Invalid pattern {pattern}: {err}")))
+        })
+        .collect()
+}
+
+fn matches(patterns: &[Pattern], relative: &Path) -> bool {
+    let name = relative.file_name().map(Path::new).unwrap_or(relative);
+    patterns.iter().any(|pattern| {
+        if pattern.as_str().contains('/') {
+            pattern.matches_path(relative)
+        } else {
+            pattern.matches_path(name)
+        }
+    })
+}
+
+impl Selection {
+    /// The selected files, sorted by path. Hidden files and folders, and the
+    /// vault itself, are skipped.
+    pub fn files(&self, vault: &Path) -> Result<Vec<PathBuf>, Error> {
+        if !self.folder.is_dir() {
+            return Err(Error::Invalid(format!("No folder {:?}", self.folder)));
+        }
+        let include = patterns(&self.include)?;
+        let exclude = patterns(&self.exclude)?;
+
+        let mut files = Vec::new();
+        let mut folders = vec![self.folder.clone()];
+        while let Some(folder) = folders.pop() {
+            let entries = fs::read_dir(&folder).map_err(|err| {
+                Error::from(err).context(&format!("Reading folder {:?} failed", folder))
+            })?;
+            for entry in entries {
+                let entry = entry.map_err(|err| {
+                    Error::from(err).context(&format!("Reading folder {:?} failed", folder))
+                })?;
+                let path = entry.path();
+                let hidden = match path.file_name().and_then(|name| name.to_str()) {
+                    Some(name) => name.starts_with('.'),
+                    None => true,
+                };
+                let relative = path.strip_prefix(&self.folder).unwrap_or(&path);
+                if hidden || path.starts_with(vault) || matches(&exclude, relative) {
+                    debug!("Skipping {:?}", path);
+                    continue;
+                }
+                // Unlike the path, the file type does not follow symlinks
+                let file_type = entry.file_type().map_err(|err| {
+                    Error::from(err).context(&format!("Reading {:?} failed", path))
+                })?;
+                if file_type.is_dir() {
+                    if self.recursive {
+                        folders.push(path);
+                    }
+                } else if file_type.is_symlink() && path.is_dir() {
+                    // Linked folders could lead back into the selection
+                    debug!("Skipping linked folder {:?}", path);
+                } else if path.is_file() && (include.is_empty() || matches(&include, relative)) {
+                    files.push(path);
+                }
+            }
+        }
+        files.sort();
+        Ok(files)
+    }
+}
+
+/// Imports the files one by one with the same metadata. The database is only
+/// locked while a single file is imported, so it stays usable meanwhile. Stops
+/// early if `cancel` is set.
+pub fn import_files(
+    db: &Mutex<Database>,
+    config: &Config,
+    files: &[PathBuf],
+    metadata: &FileMetadata,
+    allow_duplicate: bool,
+    cancel: &AtomicBool,
+    mut report: impl FnMut(Progress),
+) {
+    let total = files.len();
+    let (mut imported, mut duplicates, mut failed) = (0, 0, 0);
+    report(Progress::Started { total });
+
+    for (index, path) in files.iter().enumerate() {
+        if cancel.load(Ordering::Relaxed) {
+            break;
+        }
+        let done = index + 1;
+        let db = match db.lock() {
+            Ok(content) => content,
+            Err(_) => {
+                warn!("Locking the db mutex failed");
+                break;
+            }
+        };
+        let result = import::import_file(&db, config, path, metadata, allow_duplicate);
+        drop(db);
+
+        report(match result {
+            Ok(stored) => {
+                imported += 1;
+                Progress::Imported {
+                    path: path.clone(),
+                    stored,
+                    done,
+                    total,
+                }
+            }
+            Err(Error::Duplicate(existing)) => {
+                duplicates += 1;
+                Progress::Duplicate {
+                    path: path.clone(),
+                    existing,
+                    done,
+                    total,
+                }
+            }
+            Err(error) => {
+                warn!("Importing {:?} failed: {error}", path);
+                failed += 1;
+                Progress::Failed {
+                    path: path.clone(),
+                    error,
+                    done,
+                    total,
+                }
+            }
+        });
+    }
+
+    let cancelled = imported + duplicates + failed < total;
+    info!("Bulk import finished: {imported} imported, {duplicates} duplicates, {failed} failed");
+    report(Progress::Finished {
+        imported,
+        duplicates,
+        failed,
+        cancelled,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::SearchQuery;
+    use crate::test_util::{TempDir, TestVault};
+
+    struct TestDir {
+        _root: TempDir,
+        source: PathBuf,
+        config: Config,
+        db: Mutex<Database>,
+    }
+
+    impl TestDir {
+        fn new(name: &str) -> TestDir {
+            let TestVault {
+                root, config, db, ..
+            } = TestVault::new(
+                name,
+                serde_json::json!({
+                    "save_date": false,
+                    "categories": [],
+                    "tags": ["drive"],
+                }),
+            );
+            let source = root.join("drive");
+            for (path, content) in [
+                ("letter.txt", "Dear Olivia"),
+                ("invoice.pdf", "Invoice"),
+                (".hidden.txt", "Hidden"),
+                ("2023/notes.txt", "Notes"),
+                ("2023/archive/old.txt", "Old"),
+                ("2024/copy.txt", "Dear Olivia"),
+            ] {
+                let path = source.join(path);
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                fs::write(path, content).unwrap();
+            }
+            TestDir {
+                _root: root,
+                source,
+                config,
+                db: Mutex::new(db),
+            }
+        }
+
+        fn selection(&self, recursive: bool, include: &[&str], exclude: &[&str]) -> Selection {
+            Selection {
+                folder: self.source.clone(),
+                recursive,
+                include: include.iter().map(|p| p.to_string()).collect(),
+                exclude: exclude.iter().map(|p| p.to_string()).collect(),
+            }
+        }
+
+        fn names(&self, selection: &Selection) -> Vec<String> {
+            selection
+                .files(&self.config.folder())
+                .expect("Listing files failed")
+                .iter()
+                .map(|path| {
+                    let relative = path.strip_prefix(&self.source).unwrap();
+                    relative.to_string_lossy().to_string()
+                })
+                .collect()
+        }
+    }
+
+    #[test]
+    fn select_files() {
+        let dir = TestDir::new("bulk-select");
+        assert_eq!(
+            vec!["invoice.pdf", "letter.txt"],
+            dir.names(&dir.selection(false, &[], &[]))
+        );
+        assert_eq!(
+            vec![
+                "2023/archive/old.txt",
+                "2023/notes.txt",
+                "2024/copy.txt",
+                "invoice.pdf",
+                "letter.txt"
+            ],
+            dir.names(&dir.selection(true, &[], &[]))
+        );
+        assert_eq!(
+            vec!["2023/notes.txt", "2024/copy.txt", "letter.txt"],
+            dir.names(&dir.selection(true, &["*.txt"], &["archive"]))
+        );
+        assert_eq!(
+            vec!["2023/archive/old.txt", "2023/notes.txt"],
+            dir.names(&dir.selection(true, &["2023/**"], &[]))
+        );
+        assert!(dir
+            .selection(true, &["[a"], &[])
+            .files(&dir.config.folder())
+            .is_err());
+        // A vault inside the folder is skipped
+        assert_eq!(
+            3,
+            dir.selection(true, &[], &[])
+                .files(&dir.source.join("2023"))
+                .unwrap()
+                .len()
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn skip_linked_folders() {
+        let dir = TestDir::new("bulk-links");
+        std::os::unix::fs::symlink(&dir.source, dir.source.join("2023/loop")).unwrap();
+        std::os::unix::fs::symlink(
+            dir.source.join("letter.txt"),
+            dir.source.join("2024/letter.txt"),
+        )
+        .unwrap();
+        assert_eq!(
+            vec![
+                "2023/archive/old.txt",
+                "2023/notes.txt",
+                "2024/copy.txt",
+                "2024/letter.txt",
+                "invoice.pdf",
+                "letter.txt"
+            ],
+            dir.names(&dir.selection(true, &[], &[]))
+        );
+    }
+
+    #[test]
+    fn import_folder() {
+        let dir = TestDir::new("bulk-import");
+        let files = dir
+            .selection(true, &["*.txt"], &[])
+            .files(&dir.config.folder())
+            .unwrap();
+        fs::remove_file(dir.source.join("2023/notes.txt")).unwrap();
+        let metadata = FileMetadata {
+            tags: vec!["drive".to_string()],
+            ..Default::default()
+        };
+
+        let mut events = Vec::new();
+        import_files(
+            &dir.db,
+            &dir.config,
+            &files,
+            &metadata,
+            false,
+            &AtomicBool::new(false),
+            |progress| events.push(progress),
+        );
+        assert_eq!(Progress::Started { total: 4 }, events[0]);
+        assert!(matches!(events[1], Progress::Imported { done: 1, .. }));
+        assert!(matches!(events[2], Progress::Failed { done: 2, .. }));
+        // copy.txt has the content of letter.txt, which comes later
+        assert!(matches!(events[3], Progress::Imported { done: 3, .. }));
+        assert!(matches!(events[4], Progress::Duplicate { done: 4, .. }));
+        assert_eq!(
+            Progress::Finished {
+                imported: 2,
+                duplicates: 1,
+                failed: 1,
+                cancelled: false
+            },
+            events[5]
+        );
+
+        let db = dir.db.lock().unwrap();
+        let stored = db.search(&SearchQuery::default()).unwrap();
+        assert_eq!(2, stored.len());
+        assert!(stored.iter().all(|file| file.tags == vec!["drive"]));
+    }
+
+    #[test]
+    fn cancel_import() {
+        let dir = TestDir::new("bulk-cancel");
+        let files = dir
+            .selection(true, &[], &[])
+            .files(&dir.config.folder())
+            .unwrap();
+        let cancel = AtomicBool::new(false);
+        let mut events = Vec::new();
+        import_files(
+            &dir.db,
+            &dir.config,
+            &files,
+            &FileMetadata::default(),
+            true,
+            &cancel,
+            |progress| {
+                if matches!(progress, Progress::Imported { done: 2, .. }) {
+                    cancel.store(true, Ordering::Relaxed);
+                }
+                events.push(progress);
+            },
+        );
+        assert_eq!(
+            Some(&Progress::Finished {
+                imported: 2,
+                duplicates: 0,
+                failed: 0,
+                cancelled: true
+            }),
+            events.last()
+        );
+    }
+}
@@ -1,60 +1,596 @@
+use crate::db;
+use crate::error::Error;
+use crate::layout::Layout;
+use crate::tags;
+use crate::transfer::ImportMode;
 use dirs;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::prelude::*;
 use std::io::BufWriter;
 use std::{fs, path::PathBuf};
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Category {
     pub name: String,
     pub values: Vec<String>,
+    /// Whether a file can have several values of the category.
+    #[serde(default)]
+    pub multiple: bool,
+}
+
+/// A folder that is watched for new files, e.g. the output folder of a
+/// scanner.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Inbox {
+    pub folder: String,
+    /// Queue new files for review instead of importing them right away
+    #[serde(default)]
+    pub review: bool,
+    /// Tags of files imported without review
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Category values of files imported without review
+    #[serde(default, deserialize_with = "db::one_or_many")]
+    pub categories: HashMap<String, Vec<String>>,
+}
+
+/// Tags and category values given to every imported file that matches all
+/// conditions of the rule. A rule without conditions matches every file.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Rule {
+    pub name: String,
+    /// Regular expression the file name has to match
+    #[serde(default)]
+    pub filename: Option<String>,
+    /// Extension of the file, case insensitive
+    #[serde(default)]
+    pub extension: Option<String>,
+    /// Text the extracted content has to contain, case insensitive
+    #[serde(default)]
+    pub contains: Option<String>,
+    /// Folder the file is imported from, including its subfolders
+    #[serde(default)]
+    pub folder: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// One value or a list of values per category
+    #[serde(default, deserialize_with = "db::one_or_many")]
+    pub categories: HashMap<String, Vec<String>>,
+}
+
+/// Name of the vault of a config file from before vaults had names.
+pub const DEFAULT_VAULT: &str = "default";
+
+/// A vault of the config file, i.e. a folder with its own database. Tags and
+/// categories are stored in the database of the vault.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NamedVault {
+    pub name: String,
+    #[serde(flatten)]
+    pub config: Config,
+}
+
+/// The config file. Every vault has its own database, so files of different
+/// vaults never mix.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Vaults {
+    /// The vault that is open, and opened again at startup
+    #[serde(default)]
+    pub current: Option<String>,
+    pub vaults: Vec<NamedVault>,
+}
+
+/// A vault as listed to the user.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct VaultInfo {
+    pub name: String,
+    pub folder: String,
+    pub current: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Config {
     folder: String,
     save_date: bool,
+    /// Stored in the vault, see `Database::load_taxonomy`. Config files
+    /// written before still contain them.
+    #[serde(default)]
     pub categories: Vec<Category>,
+    #[serde(default)]
     pub tags: Vec<String>,
+    /// Other names of tags, with the tag they stand for
+    #[serde(default)]
+    pub aliases: HashMap<String, String>,
+    #[serde(default)]
+    pub inbox: Option<Inbox>,
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+    /// How new files are stored in the vault
+    #[serde(default)]
+    pub layout: Layout,
+    /// Whether imported files are copied, moved or linked into the vault
+    #[serde(default)]
+    pub import_mode: ImportMode,
 }
 
+/// The tags and category values of a rule or the inbox.
+type Assignment<'a> = (&'a mut Vec<String>, &'a mut HashMap<String, Vec<String>>);
+
 impl Config {
-    pub fn store(&self) -> std::io::Result<()> {
-        let mut file = BufWriter::new(File::create(Config::config_path())?);
+    /// Stores the config as the config of the open vault. Tags and categories
+    /// are left out, they have to be stored in the vault first.
+    pub fn store(&self) -> Result<(), Error> {
+        let mut vaults = Vaults::load()?;
+        vaults.set_current(self.without_taxonomy())?;
+        vaults.store()
+    }
+
+    /// The config of the open vault.
+    pub fn load() -> Result<Config, Error> {
+        Vaults::load()?.current().cloned()
+    }
+
+    /// Without a config directory there is nowhere to keep the config.
+    fn config_path() -> Result<PathBuf, Error> {
+        match dirs::config_dir() {
+            Some(config_dir) => Ok(config_dir.join("files").join("config.json")),
+            None => Err(Error::NotConfigured),
+        }
+    }
+
+    /// The config as it is stored in the config file, without the tags,
+    /// aliases and categories of the vault.
+    pub fn without_taxonomy(&self) -> Config {
+        Config {
+            categories: Vec::new(),
+            tags: Vec::new(),
+            aliases: HashMap::new(),
+            ..self.clone()
+        }
+    }
+
+    /// Whether the config still contains tags or categories, which have to be
+    /// imported into the vault.
+    pub fn has_taxonomy(&self) -> bool {
+        !self.tags.is_empty() || !self.categories.is_empty() || !self.aliases.is_empty()
+    }
+
+    pub fn db_path(&self) -> PathBuf {
+        PathBuf::from(&self.folder).join("files.sqlite")
+    }
 
-        file.write_all(serde_json::to_vec_pretty(self).unwrap().as_slice())?;
-        file.flush()?;
+    pub fn folder(&self) -> PathBuf {
+        PathBuf::from(&self.folder)
+    }
+
+    pub fn save_date(&self) -> bool {
+        self.save_date
+    }
+
+    /// The tags and category values assigned by the inbox and the rules,
+    /// which have to follow renames.
+    fn assignments(&mut self) -> Vec<Assignment<'_>> {
+        let mut assignments: Vec<Assignment> = self
+            .rules
+            .iter_mut()
+            .map(|rule| (&mut rule.tags, &mut rule.categories))
+            .collect();
+        if let Some(ref mut inbox) = self.inbox {
+            assignments.push((&mut inbox.tags, &mut inbox.categories));
+        }
+        assignments
+    }
+
+    /// Renames the tag and its descendants, see `Database::rename_tag`.
+    pub fn rename_tag(&mut self, old: &String, new: &str) -> Result<(), Error> {
+        tags::check(new)?;
+        if new != old && tags::is_within(new, old) {
+            return Err(Error::Invalid(format!(
+                "Tag {old} can not be moved below itself"
+            )));
+        }
+        if !self.tags.contains(old) {
+            return Err(Error::UnknownTag(old.clone()));
+        }
+        if let Some(taken) = self.tags.iter().find(|tag| {
+            !tags::is_within(tag, old)
+                && self
+                    .tags
+                    .iter()
+                    .any(|t| tags::renamed(t, old, new).as_ref() == Some(*tag))
+        }) {
+            return Err(Error::Conflict(format!("Tag {taken} already exists")));
+        }
+        for tag in self.tags.iter_mut() {
+            if let Some(name) = tags::renamed(tag, old, new) {
+                *tag = name;
+            }
+        }
+        for tag in self.aliases.values_mut() {
+            if let Some(name) = tags::renamed(tag, old, new) {
+                *tag = name;
+            }
+        }
+        for (assigned, _) in self.assignments() {
+            for tag in assigned.iter_mut() {
+                if let Some(name) = tags::renamed(tag, old, new) {
+                    *tag = name;
+                }
+            }
+        }
         Ok(())
     }
 
-    pub fn load() -> Result<Config, String> {
-        let config = match fs::read_to_string(Config::config_path()) {
-            Ok(content) => content,
-            Err(error) => return Err(error.to_string()),
+    /// Folds the tag `from` into `into`, see `Database::merge_tags`.
+    pub fn merge_tag(&mut self, from: &String, into: &String) -> Result<(), Error> {
+        let into = self.aliases.get(into).unwrap_or(into).clone();
+        if !self.tags.contains(&into) {
+            return Err(Error::UnknownTag(into));
+        }
+        if *from == into {
+            return Err(Error::Invalid(format!(
+                "Tag {from} can not be merged into itself"
+            )));
+        }
+        if self
+            .tags
+            .iter()
+            .any(|tag| tag != from && tags::is_within(tag, from))
+        {
+            return Err(Error::Invalid(format!(
+                "Tag {from} has subtags, move them first"
+            )));
+        }
+        match self.tags.iter().position(|tag| tag == from) {
+            Some(index) => self.tags.remove(index),
+            None => return Err(Error::UnknownTag(from.clone())),
         };
+        for tag in self.aliases.values_mut().filter(|tag| *tag == from) {
+            *tag = into.clone();
+        }
+        self.aliases.insert(from.clone(), into.clone());
+        for (assigned, _) in self.assignments() {
+            for tag in assigned.iter_mut().filter(|tag| *tag == from) {
+                *tag = into.clone();
+            }
+            let mut seen = Vec::new();
+            assigned.retain(|tag| {
+                let first = !seen.contains(tag);
+                seen.push(tag.clone());
+                first
+            });
+        }
+        Ok(())
+    }
+
+    pub fn rename_category(&mut self, old: &String, new: &String) -> Result<(), Error> {
+        if self.categories.iter().any(|c| c.name == *new) {
+            return Err(Error::Conflict(format!("Category {new} already exists")));
+        }
+        match self.categories.iter_mut().find(|c| c.name == *old) {
+            Some(category) => category.name = new.clone(),
+            None => return Err(Error::UnknownCategory(old.clone())),
+        }
+        for (_, categories) in self.assignments() {
+            if let Some(values) = categories.remove(old) {
+                categories.insert(new.clone(), values);
+            }
+        }
+        Ok(())
+    }
 
-        let config: Config = match serde_json::from_str(&config) {
-            Ok(content) => content,
-            Err(error) => return Err(error.to_string()),
+    pub fn rename_category_value(
+        &mut self,
+        category: &String,
+        old: &String,
+        new: &String,
+    ) -> Result<(), Error> {
+        let values = match self.categories.iter_mut().find(|c| c.name == *category) {
+            Some(category) => &mut category.values,
+            None => return Err(Error::UnknownCategory(category.clone())),
         };
+        if values.contains(new) {
+            return Err(Error::Conflict(format!("Value {new} already exists")));
+        }
+        match values.iter_mut().find(|value| *value == old) {
+            Some(value) => *value = new.clone(),
+            None => return Err(Error::UnknownCategoryValue(category.clone(), old.clone())),
+        }
+        for (_, categories) in self.assignments() {
+            for value in categories.get_mut(category).into_iter().flatten() {
+                if value == old {
+                    *value = new.clone();
+                }
+            }
+        }
+        Ok(())
+    }
+}
 
-        Ok(config)
+impl Vaults {
+    pub fn load() -> Result<Vaults, Error> {
+        match fs::read_to_string(Config::config_path()?) {
+            Ok(content) => Vaults::parse(&content),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(Vaults::default()),
+            Err(error) => Err(Error::from(error).context("Failed to read config")),
+        }
     }
 
-    fn config_path() -> PathBuf {
-        if let Some(config_dir) = dirs::config_dir() {
-            config_dir.join("files").join("config.json")
-        } else {
-            panic!("Config directory not found.")
+    /// Reads a config file. A file with a single config, from before there
+    /// were several vaults, becomes the default vault.
+    fn parse(content: &str) -> Result<Vaults, Error> {
+        let value: serde_json::Value = serde_json::from_str(content)
+            .map_err(|error| Error::Invalid(format!("Invalid config: {error}")))?;
+        if value.get("vaults").is_some() {
+            return serde_json::from_value(value)
+                .map_err(|error| Error::Invalid(format!("Invalid config: {error}")));
         }
+        let config: Config = serde_json::from_value(value)
+            .map_err(|error| Error::Invalid(format!("Invalid config: {error}")))?;
+        Ok(Vaults {
+            current: Some(DEFAULT_VAULT.to_string()),
+            vaults: vec![NamedVault {
+                name: DEFAULT_VAULT.to_string(),
+                config,
+            }],
+        })
     }
 
-    pub fn db_path(&self) -> PathBuf {
-        PathBuf::from(&self.folder).join("files.sqlite")
+    pub fn store(&self) -> Result<(), Error> {
+        let path = Config::config_path()?;
+        // Serialized first, so a failure leaves the config file untouched
+        let content = serde_json::to_vec_pretty(self)
+            .map_err(|error| Error::Internal(format!("Failed to store config: {error}")))?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .map_err(|error| Error::from(error).context("Failed to store config"))?;
+        }
+        let mut file = BufWriter::new(
+            File::create(path)
+                .map_err(|error| Error::from(error).context("Failed to store config"))?,
+        );
+        file.write_all(content.as_slice())
+            .and_then(|()| file.flush())
+            .map_err(|error| Error::from(error).context("Failed to store config"))
     }
 
-    pub fn folder(&self) -> PathBuf {
-        PathBuf::from(&self.folder)
+    pub fn get(&self, name: &str) -> Result<&Config, Error> {
+        self.vaults
+            .iter()
+            .find(|vault| vault.name == name)
+            .map(|vault| &vault.config)
+            .ok_or_else(|| Error::UnknownVault(name.to_string()))
+    }
+
+    /// The config of the open vault.
+    pub fn current(&self) -> Result<&Config, Error> {
+        match self.current {
+            Some(ref name) => self.get(name),
+            None => Err(Error::NotConfigured),
+        }
+    }
+
+    /// Makes the vault the open one and returns its config.
+    pub fn select(&mut self, name: &str) -> Result<&Config, Error> {
+        self.get(name)?;
+        self.current = Some(name.to_string());
+        self.get(name)
+    }
+
+    /// A vault must not share its folder with another vault.
+    fn check_folder(&self, name: &str, config: &Config) -> Result<(), Error> {
+        match self
+            .vaults
+            .iter()
+            .find(|vault| vault.name != name && vault.config.folder() == config.folder())
+        {
+            Some(other) => Err(Error::Conflict(format!(
+                "{} is already the folder of vault {}",
+                config.folder, other.name
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    pub fn add(&mut self, name: &str, config: Config) -> Result<(), Error> {
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(Error::Invalid("A vault needs a name".to_string()));
+        }
+        if self.vaults.iter().any(|vault| vault.name == name) {
+            return Err(Error::Conflict(format!("Vault {name} already exists")));
+        }
+        self.check_folder(name, &config)?;
+        self.vaults.push(NamedVault {
+            name: name.to_string(),
+            config,
+        });
+        Ok(())
+    }
+
+    /// Replaces the config of the open vault. Without an open vault, the
+    /// config becomes the default vault and is opened.
+    pub fn set_current(&mut self, config: Config) -> Result<(), Error> {
+        let name = self
+            .current
+            .clone()
+            .unwrap_or_else(|| DEFAULT_VAULT.to_string());
+        self.check_folder(&name, &config)?;
+        match self.vaults.iter_mut().find(|vault| vault.name == name) {
+            Some(vault) => vault.config = config,
+            None => self.vaults.push(NamedVault {
+                name: name.clone(),
+                config,
+            }),
+        }
+        self.current = Some(name);
+        Ok(())
+    }
+
+    pub fn list(&self) -> Vec<VaultInfo> {
+        self.vaults
+            .iter()
+            .map(|vault| VaultInfo {
+                name: vault.name.clone(),
+                folder: vault.config.folder.clone(),
+                current: self.current.as_ref() == Some(&vault.name),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(folder: &str) -> Config {
+        serde_json::from_value(serde_json::json!({
+            "folder": folder,
+            "save_date": true,
+            "categories": [],
+            "tags": ["taxes"],
+        }))
+        .expect("Invalid config")
+    }
+
+    #[test]
+    fn single_config_file() {
+        let vaults = Vaults::parse(
+            r#"{"folder": "/files", "save_date": true, "categories": [], "tags": ["taxes"]}"#,
+        )
+        .expect("Parsing failed");
+        assert_eq!(Some(DEFAULT_VAULT.to_string()), vaults.current);
+        assert_eq!(PathBuf::from("/files"), vaults.current().unwrap().folder());
+        assert!(Vaults::parse(r#"{"folder": "/files"}"#).is_err());
+    }
+
+    #[test]
+    fn named_vaults() {
+        let mut vaults = Vaults::default();
+        assert_eq!(Err(Error::NotConfigured), vaults.current().map(|_| ()));
+        vaults.set_current(config("/work")).unwrap();
+        vaults.add("personal", config("/personal")).unwrap();
+        assert!(matches!(
+            vaults.add("personal", config("/other")),
+            Err(Error::Conflict(_))
+        ));
+        assert!(matches!(
+            vaults.add("private", config("/work")),
+            Err(Error::Conflict(_))
+        ));
+        assert!(matches!(
+            vaults.add(" ", config("/other")),
+            Err(Error::Invalid(_))
+        ));
+
+        assert_eq!(
+            Err(Error::UnknownVault("home".to_string())),
+            vaults.select("home").map(|_| ())
+        );
+        assert_eq!(
+            PathBuf::from("/personal"),
+            vaults.select("personal").unwrap().folder()
+        );
+        assert_eq!(
+            vec![
+                VaultInfo {
+                    name: DEFAULT_VAULT.to_string(),
+                    folder: "/work".to_string(),
+                    current: false
+                },
+                VaultInfo {
+                    name: "personal".to_string(),
+                    folder: "/personal".to_string(),
+                    current: true
+                }
+            ],
+            vaults.list()
+        );
+        // The open vault can not take the folder of another one
+        assert!(vaults.set_current(config("/work")).is_err());
+
+        let parsed =
+            Vaults::parse(r#"{"vaults": [{"name": "nas", "folder": "/nas", "save_date": true}]}"#)
+                .expect("Parsing failed");
+        assert!(!parsed.get("nas").unwrap().has_taxonomy());
+        assert!(!config("/work").without_taxonomy().has_taxonomy());
+
+        let stored = serde_json::to_string(&vaults).unwrap();
+        let parsed = Vaults::parse(&stored).expect("Parsing failed");
+        assert_eq!(vaults.list(), parsed.list());
+        assert_eq!(vec!["taxes"], parsed.current().unwrap().tags);
+    }
+
+    #[test]
+    fn rename_tag_subtree() {
+        let mut config = config("/files");
+        config.tags = vec![
+            "finance".to_string(),
+            "finance/tax".to_string(),
+            "financial".to_string(),
+            "money".to_string(),
+            "money/tax".to_string(),
+        ];
+        config.inbox = Some(Inbox {
+            folder: "/scans".to_string(),
+            tags: vec!["finance/tax".to_string()],
+            ..Default::default()
+        });
+        assert_eq!(
+            Err(Error::Conflict("Tag money already exists".to_string())),
+            config.rename_tag(&"finance".to_string(), "money")
+        );
+        assert!(matches!(
+            config.rename_tag(&"finance".to_string(), "finance/old"),
+            Err(Error::Invalid(_))
+        ));
+        assert!(matches!(
+            config.rename_tag(&"finance".to_string(), "archive//finance"),
+            Err(Error::Invalid(_))
+        ));
+        config
+            .rename_tag(&"finance".to_string(), "archive/finance")
+            .unwrap();
+        assert_eq!(
+            vec![
+                "archive/finance",
+                "archive/finance/tax",
+                "financial",
+                "money",
+                "money/tax"
+            ],
+            config.tags
+        );
+        assert_eq!(vec!["archive/finance/tax"], config.inbox.unwrap().tags);
+    }
+
+    #[test]
+    fn merge_tag() {
+        let mut config = config("/files");
+        config.tags = vec!["insurance".to_string(), "ins".to_string()];
+        config.aliases = HashMap::from([("insur".to_string(), "ins".to_string())]);
+        config.inbox = Some(Inbox {
+            folder: "/scans".to_string(),
+            tags: vec!["ins".to_string(), "insurance".to_string()],
+            ..Default::default()
+        });
+        assert!(matches!(
+            config.merge_tag(&"ins".to_string(), &"insur".to_string()),
+            Err(Error::Invalid(_))
+        ));
+        config
+            .merge_tag(&"ins".to_string(), &"insurance".to_string())
+            .unwrap();
+        assert_eq!(vec!["insurance"], config.tags);
+        assert_eq!(
+            HashMap::from([
+                ("ins".to_string(), "insurance".to_string()),
+                ("insur".to_string(), "insurance".to_string())
+            ]),
+            config.aliases
+        );
+        assert_eq!(vec!["insurance"], config.inbox.unwrap().tags);
     }
 }
@@ -1,22 +1,256 @@
-use crate::config::Category;
-
-use super::Config;
+use crate::config::{Category, Config};
+use crate::error::Error;
+use crate::layout::{self, LayoutKind};
+use crate::migration;
+use crate::query::{self, Expr};
+use crate::tags;
 use log::{debug, info, warn};
-use rusqlite::{params, Connection, Result};
-use std::path::PathBuf;
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Conditions for `fileTags` and `fileValues` rows without a file, tag or
+/// value, e.g. left behind while foreign keys were not enforced.
+const DANGLING_TAGS: &str = "file_id IS NULL OR tag_id IS NULL OR file_id NOT IN (SELECT id FROM files) OR tag_id NOT IN (SELECT id FROM tags)";
+const DANGLING_VALUES: &str = "file_id IS NULL OR value_id IS NULL OR file_id NOT IN (SELECT id FROM files) OR value_id NOT IN (SELECT id FROM categoryValue)";
 
 #[derive(Debug)]
 struct CategoryEntry {
     id: u32,
     name: String,
+    multiple: bool,
 }
 
 impl PartialEq for CategoryEntry {
     fn eq(&self, other: &Self) -> bool {
-        self.id == other.id && self.name == other.name
+        self.id == other.id && self.name == other.name && self.multiple == other.multiple
+    }
+}
+
+/// Constraints for a file search. A file matches if it has all `tags`, none of
+/// the `excluded_tags`, any or all of the given values for every category in
+/// `categories` (see `value_match`) and a date between `date_from` and
+/// `date_to` (inclusive), if set.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct SearchQuery {
+    pub tags: Vec<String>,
+    pub excluded_tags: Vec<String>,
+    #[serde(deserialize_with = "one_or_many")]
+    pub categories: HashMap<String, Vec<String>>,
+    pub value_match: ValueMatch,
+    pub date_from: Option<String>,
+    pub date_to: Option<String>,
+    pub sort: SortOrder,
+}
+
+/// How the values given for a category in a search are combined.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ValueMatch {
+    /// The file has at least one of the values.
+    #[default]
+    Any,
+    /// The file has every one of the values.
+    All,
+}
+
+/// Accepts a single value per category as well as a list, searches used to
+/// take only one.
+pub(crate) fn one_or_many<'de, D>(deserializer: D) -> Result<HashMap<String, Vec<String>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Values {
+        One(String),
+        Many(Vec<String>),
+    }
+    let map = HashMap::<String, Values>::deserialize(deserializer)?;
+    Ok(map
+        .into_iter()
+        .map(|(category, values)| match values {
+            Values::One(value) => (category, vec![value]),
+            Values::Many(values) => (category, values),
+        })
+        .collect())
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    #[default]
+    Name,
+    /// Oldest document first, files without a date last.
+    Date,
+    /// Newest document first, files without a date last.
+    DateDescending,
+    /// Most recently imported first.
+    Imported,
+}
+
+impl SortOrder {
+    fn sql(&self) -> &'static str {
+        match self {
+            SortOrder::Name => "name",
+            SortOrder::Date => "date IS NULL, date, name",
+            SortOrder::DateDescending => "date IS NULL, date DESC, name",
+            SortOrder::Imported => "imported_at DESC, name",
+        }
+    }
+}
+
+/// Tags, category values and document date assigned to a file.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct FileMetadata {
+    pub tags: Vec<String>,
+    pub categories: HashMap<String, Vec<String>>,
+    /// Date of the document as `YYYY-MM-DD`.
+    pub date: Option<String>,
+}
+
+impl SearchQuery {
+    fn to_expr(&self) -> Option<Expr> {
+        let included = self.tags.iter().map(|t| Expr::Tag(t.clone()));
+        let excluded = self
+            .excluded_tags
+            .iter()
+            .map(|t| Expr::Not(Box::new(Expr::Tag(t.clone()))));
+        let values = self.categories.iter().filter_map(|(category, values)| {
+            let values = values
+                .iter()
+                .map(|value| Expr::Value(category.clone(), value.clone()))
+                .collect();
+            match self.value_match {
+                ValueMatch::Any => Expr::any(values),
+                ValueMatch::All => Expr::all(values),
+            }
+        });
+        let date = match (&self.date_from, &self.date_to) {
+            (None, None) => None,
+            (from, to) => Some(Expr::Date(from.clone(), to.clone())),
+        };
+        Expr::all(included.chain(excluded).chain(values).chain(date).collect())
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct FileEntry {
+    pub path: String,
+    pub name: String,
+    pub tags: Vec<String>,
+    /// The values of every category, sorted.
+    pub categories: HashMap<String, Vec<String>>,
+    pub date: Option<String>,
+    /// Unix timestamp of the import.
+    pub imported_at: Option<i64>,
+    pub layout: LayoutKind,
+}
+
+impl FileEntry {
+    /// Where the file is stored, relative to the vault.
+    pub fn relative_location(&self) -> PathBuf {
+        self.layout.relative_location(&self.path, &self.name)
+    }
+
+    pub fn location(&self, vault: &Path) -> PathBuf {
+        vault.join(self.relative_location())
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct TextHit {
+    pub file: FileEntry,
+    /// Matching part of the content, matches are wrapped in `<mark>` tags.
+    pub snippet: String,
+    pub rank: f64,
+}
+
+/// Another name of a tag.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Alias {
+    pub alias: String,
+    pub tag: String,
+}
+
+/// A tag or category that is no longer in the config.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Removal {
+    pub name: String,
+    /// Number of files that would lose the tag or category.
+    pub files: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ValueChange {
+    pub category: String,
+    pub value: String,
+    /// Number of files that would lose the value.
+    pub files: u32,
+}
+
+/// Changes needed to bring the database in line with the config.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct ConsistencyPlan {
+    pub added_tags: Vec<String>,
+    pub removed_tags: Vec<Removal>,
+    pub added_aliases: Vec<Alias>,
+    pub removed_aliases: Vec<Alias>,
+    /// Tags that became an alias in the config, their files keep them
+    pub merged_tags: Vec<Alias>,
+    pub added_categories: Vec<Category>,
+    pub removed_categories: Vec<Removal>,
+    /// Categories that switched between a single and multiple values
+    pub changed_categories: Vec<Category>,
+    pub added_values: Vec<ValueChange>,
+    pub removed_values: Vec<ValueChange>,
+}
+
+impl ConsistencyPlan {
+    /// Whether applying the plan would remove tags or values from files.
+    pub fn is_destructive(&self) -> bool {
+        self.removed_tags.iter().any(|r| r.files > 0)
+            || self.removed_categories.iter().any(|r| r.files > 0)
+            || self.removed_values.iter().any(|v| v.files > 0)
+    }
+
+    /// Destructive plans have to be confirmed by the user, and what the user
+    /// confirmed has to be the plan that is about to be applied.
+    pub fn check_confirmed(&self, confirmed: Option<&ConsistencyPlan>) -> Result<(), Error> {
+        if !self.is_destructive() {
+            return Ok(());
+        }
+        match confirmed {
+            Some(confirmed) if confirmed == self => Ok(()),
+            Some(_) => Err(Error::Conflict(
+                "The tags or values in use changed since the changes were confirmed".to_string(),
+            )),
+            None => Err(Error::Conflict(
+                "The new config removes tags or values that are still used by files".to_string(),
+            )),
+        }
+    }
+
+    /// The plan without removals of anything still used by files.
+    pub fn safe(&self) -> ConsistencyPlan {
+        let mut plan = self.clone();
+        plan.removed_tags.retain(|r| r.files == 0);
+        plan.removed_categories.retain(|r| r.files == 0);
+        plan.removed_values.retain(|v| v.files == 0);
+        plan
     }
 }
 
+/// How long to wait for a lock of another process, e.g. while the app and
+/// the command-line interface write at once.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Default)]
 pub struct Database {
     connection: Option<Connection>,
 }
@@ -26,16 +260,21 @@ impl Database {
         Database { connection: None }
     }
 
-    pub fn open(&mut self, path: PathBuf) -> Result<()> {
+    pub fn open(&mut self, path: PathBuf) -> Result<(), Error> {
         info!("Opening database at {0}", path.display());
-        self.connection = match Connection::open(path) {
-            Ok(con) => Some(con),
+        let con = match Connection::open(&path) {
+            Ok(con) => con,
             Err(err) => {
-                return Err(err);
+                return Err(Error::from(err).context("Failed to open database"));
             }
         };
-        if self.is_initialized() {
-            self.create_tables().expect("Failed to generate tables");
+        if let Err(err) = con.busy_timeout(BUSY_TIMEOUT) {
+            return Err(Error::from(err).context("Failed to open database"));
+        }
+        self.connection = Some(con);
+        if let Err(err) = self.migrate(Some(&path)) {
+            self.connection = None;
+            return Err(err.context("Failed to migrate database"));
         }
         Ok(())
     }
@@ -44,192 +283,410 @@ impl Database {
         self.connection.is_some()
     }
 
-    fn get_tags(&self) -> Result<Vec<String>> {
-        if let Some(ref con) = self.connection {
-            con.prepare("SELECT tag FROM tags")
-                .expect("Tag select failed")
-                .query_map([], |row| row.get(0))?
-                .collect::<Result<Vec<String>>>()
-        } else {
-            // TODO not very clean (has to be chanaged eveywhere)
-            Err(rusqlite::Error::InvalidQuery)
-        }
+    fn connection(&self) -> Result<&Connection, Error> {
+        self.connection.as_ref().ok_or(Error::DatabaseClosed)
     }
 
-    fn delete_tag(&self, tag: &String) -> Result<usize> {
-        if let Some(ref con) = self.connection {
-            con.execute("DELETE FROM tags WHERE tag = ?1", params![tag])
-        } else {
-            Err(rusqlite::Error::InvalidQuery)
-        }
+    fn get_tags(&self) -> Result<Vec<String>, Error> {
+        let tags = self
+            .connection()?
+            .prepare("SELECT tag FROM tags WHERE canonical_id IS NULL ORDER BY id")?
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+        Ok(tags)
     }
 
-    fn insert_tag(&self, tag: &String) -> Result<usize> {
-        if let Some(ref con) = self.connection {
-            con.execute("INSERT INTO tags(tag) VALUES (?1)", params![tag])
-        } else {
-            Err(rusqlite::Error::InvalidQuery)
-        }
+    fn delete_tag(&self, tag: &String) -> Result<usize, Error> {
+        Ok(self
+            .connection()?
+            .execute("DELETE FROM tags WHERE tag = ?1", params![tag])?)
     }
 
-    fn get_tag_id(&self, tag: &String) -> Result<u32> {
-        if let Some(ref con) = self.connection {
-            con.query_row("SELECT id FROM tags WHERE tag = ?1", params![tag], |r| {
-                r.get(0)
-            })
-        } else {
-            Err(rusqlite::Error::InvalidQuery)
+    /// Creates the missing ancestors of the tag and returns the id of its
+    /// parent.
+    fn insert_ancestors(&self, tag: &str) -> Result<Option<u32>, Error> {
+        let mut parent = None;
+        for ancestor in tags::ancestors(tag) {
+            self.connection()?.execute(
+                "INSERT OR IGNORE INTO tags(tag, parent_id) VALUES (?1, ?2)",
+                params![ancestor, parent],
+            )?;
+            parent = Some(self.get_tag_id(&ancestor.to_string())?);
         }
+        Ok(parent)
     }
 
-    fn tag_consistency(&self, config_tags: &Vec<String>) -> Result<()> {
-        let tags = self.get_tags()?;
+    fn insert_tag(&self, tag: &String) -> Result<usize, Error> {
+        tags::check(tag)?;
+        let parent = self.insert_ancestors(tag)?;
+        Ok(self.connection()?.execute(
+            "INSERT INTO tags(tag, parent_id) VALUES (?1, ?2)",
+            params![tag, parent],
+        )?)
+    }
 
-        // Delete unused tags
-        for tag in tags.iter() {
-            if !config_tags.contains(tag) {
-                debug!("Deleting tag {}", tag);
-                self.delete_tag(tag)?;
-            }
+    fn get_tag_id(&self, tag: &String) -> Result<u32, Error> {
+        self.connection()?
+            .query_row(
+                "SELECT id FROM tags WHERE tag = ?1 AND canonical_id IS NULL",
+                params![tag],
+                |r| r.get(0),
+            )
+            .optional()?
+            .ok_or_else(|| Error::UnknownTag(tag.clone()))
+    }
+
+    /// The id of the tag, or of the tag the alias stands for.
+    fn get_canonical_tag_id(&self, tag: &String) -> Result<u32, Error> {
+        self.connection()?
+            .query_row(
+                "SELECT COALESCE(canonical_id, id) FROM tags WHERE tag = ?1",
+                params![tag],
+                |r| r.get(0),
+            )
+            .optional()?
+            .ok_or_else(|| Error::UnknownTag(tag.clone()))
+    }
+
+    /// The name of the tag, or of the tag the alias stands for.
+    pub fn resolve_tag(&self, tag: &String) -> Result<String, Error> {
+        let id = self.get_canonical_tag_id(tag)?;
+        Ok(self.connection()?.query_row(
+            "SELECT tag FROM tags WHERE id = ?1",
+            params![id],
+            |r| r.get(0),
+        )?)
+    }
+
+    /// The aliases with the tags they stand for.
+    fn get_aliases(&self) -> Result<HashMap<String, String>, Error> {
+        let aliases = self
+            .connection()?
+            .prepare("SELECT aliases.tag, tags.tag FROM tags AS aliases JOIN tags ON aliases.canonical_id = tags.id")?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<HashMap<String, String>>>()?;
+        Ok(aliases)
+    }
+
+    /// Adds another name for the tag, which can be used wherever the tag can.
+    pub fn add_alias(&self, alias: &String, tag: &String) -> Result<(), Error> {
+        info!("Adding alias {alias} of tag {tag}");
+        tags::check(alias)?;
+        let tag_id = self.get_tag_id(tag)?;
+        self.connection()?
+            .execute(
+                "INSERT INTO tags(tag, canonical_id) VALUES (?1, ?2)",
+                params![alias, tag_id],
+            )
+            .map_err(|err| {
+                conflict(err, format!("Tag or alias {alias} already exists"))
+                    .context("Failed to add alias")
+            })?;
+        Ok(())
+    }
+
+    pub fn remove_alias(&self, alias: &String) -> Result<(), Error> {
+        info!("Removing alias {alias}");
+        match self.connection()?.execute(
+            "DELETE FROM tags WHERE tag = ?1 AND canonical_id IS NOT NULL",
+            params![alias],
+        ) {
+            Ok(0) => Err(Error::UnknownTag(alias.clone())),
+            Ok(_) => Ok(()),
+            Err(err) => Err(Error::from(err).context("Failed to remove alias")),
         }
+    }
 
-        // Insert new tags
-        for tag in config_tags.iter() {
-            if !tags.contains(tag) {
-                debug!("Inserting tag {}", tag);
-                self.insert_tag(tag)?;
-            }
+    /// Folds the tag `from` into `into`: its files get `into` instead, and
+    /// `from` and its aliases become aliases of `into`.
+    fn merge_tag(&self, from: &String, into: &String) -> Result<(), Error> {
+        let con = self.connection()?;
+        let from_id = self.get_tag_id(from)?;
+        let into_id = self.get_canonical_tag_id(into)?;
+        if from_id == into_id {
+            return Err(Error::Invalid(format!(
+                "Tag {from} can not be merged into itself"
+            )));
         }
+        let children: u32 = con.query_row(
+            "SELECT COUNT(*) FROM tags WHERE parent_id = ?1",
+            params![from_id],
+            |r| r.get(0),
+        )?;
+        if children > 0 {
+            return Err(Error::Invalid(format!(
+                "Tag {from} has subtags, move them first"
+            )));
+        }
+
+        con.execute(
+            "UPDATE fileTags SET tag_id = ?2 WHERE tag_id = ?1 AND file_id NOT IN (SELECT file_id FROM fileTags WHERE tag_id = ?2)",
+            params![from_id, into_id],
+        )?;
+        con.execute("DELETE FROM fileTags WHERE tag_id = ?1", params![from_id])?;
+        con.execute(
+            "UPDATE tags SET canonical_id = ?2 WHERE canonical_id = ?1",
+            params![from_id, into_id],
+        )?;
+        con.execute(
+            "UPDATE tags SET canonical_id = ?2, parent_id = NULL WHERE id = ?1",
+            params![from_id, into_id],
+        )?;
         Ok(())
     }
 
-    fn get_categories(&self) -> Result<Vec<CategoryEntry>> {
-        if let Some(ref con) = self.connection {
-            con.prepare("SELECT id, name FROM categories")?
-                .query_map([], |row| {
-                    Ok(CategoryEntry {
-                        id: row.get(0)?,
-                        name: row.get(1)?,
-                    })
-                })?
-                .collect::<Result<Vec<CategoryEntry>>>()
-        } else {
-            Err(rusqlite::Error::InvalidQuery)
-        }
+    /// Merges the tag `from` into `into`, or changes nothing if it fails.
+    pub fn merge_tags(&self, from: &String, into: &String) -> Result<(), Error> {
+        info!("Merging tag {from} into {into}");
+        let tx = self
+            .connection()?
+            .unchecked_transaction()
+            .map_err(|err| Error::from(err).context("Failed to start transaction"))?;
+        self.merge_tag(from, into)
+            .map_err(|err| err.context("Failed to merge tag"))?;
+        tx.commit()
+            .map_err(|err| Error::from(err).context("Failed to merge tag"))
+    }
+
+    /// Files with the tag or one of its descendants, which lose it if the tag
+    /// is removed.
+    fn count_tag_files(&self, tag: &String) -> Result<u32, Error> {
+        Ok(self.connection()?.query_row(
+            &format!(
+                "SELECT COUNT(DISTINCT file_id) FROM fileTags WHERE tag_id IN ({})",
+                query::tag_subtree(1)
+            ),
+            params![tag],
+            |r| r.get(0),
+        )?)
     }
 
-    fn get_category_id(&self, name: &String) -> Result<u32> {
-        if let Some(ref con) = self.connection {
-            con.query_row(
+    fn get_categories(&self) -> Result<Vec<CategoryEntry>, Error> {
+        let categories = self
+            .connection()?
+            .prepare("SELECT id, name, multiple FROM categories ORDER BY id")?
+            .query_map([], |row| {
+                Ok(CategoryEntry {
+                    id: row.get(0)?,
+                    name: row.get(1)?,
+                    multiple: row.get(2)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<CategoryEntry>>>()?;
+        Ok(categories)
+    }
+
+    fn get_category_id(&self, name: &String) -> Result<u32, Error> {
+        self.connection()?
+            .query_row(
                 "SELECT id FROM categories WHERE name = ?1",
                 params![name],
                 |r| r.get(0),
             )
-        } else {
-            Err(rusqlite::Error::InvalidQuery)
-        }
+            .optional()?
+            .ok_or_else(|| Error::UnknownCategory(name.clone()))
     }
 
-    fn delete_category(&self, id: u32) -> Result<usize> {
-        if let Some(ref con) = self.connection {
-            con.execute("DELETE FROM categories WHERE id = ?1", params![id])
-        } else {
-            Err(rusqlite::Error::InvalidQuery)
-        }
+    fn delete_category(&self, id: u32) -> Result<usize, Error> {
+        Ok(self
+            .connection()?
+            .execute("DELETE FROM categories WHERE id = ?1", params![id])?)
     }
 
-    fn insert_category(&self, name: &String) -> Result<usize> {
-        if let Some(ref con) = self.connection {
-            con.execute("INSERT INTO categories(name) VALUES (?1)", params![name])
-        } else {
-            Err(rusqlite::Error::InvalidQuery)
+    fn insert_category(&self, name: &String) -> Result<usize, Error> {
+        Ok(self
+            .connection()?
+            .execute("INSERT INTO categories(name) VALUES (?1)", params![name])?)
+    }
+
+    fn is_multiple(&self, category_id: u32) -> Result<bool, Error> {
+        Ok(self.connection()?.query_row(
+            "SELECT multiple FROM categories WHERE id = ?1",
+            params![category_id],
+            |row| row.get(0),
+        )?)
+    }
+
+    /// Switches a category between a single and multiple values per file. A
+    /// category can only take a single value again if no file has more.
+    fn set_multiple(&self, name: &String, multiple: bool) -> Result<(), Error> {
+        let category_id = self.get_category_id(name)?;
+        if !multiple {
+            let files: u32 = self.connection()?.query_row(
+                "SELECT COUNT(*) FROM (SELECT fileValues.file_id FROM fileValues JOIN categoryValue ON fileValues.value_id = categoryValue.id WHERE categoryValue.category_id = ?1 GROUP BY fileValues.file_id HAVING COUNT(*) > 1)",
+                params![category_id],
+                |row| row.get(0),
+            )?;
+            if files > 0 {
+                return Err(Error::Conflict(format!(
+                    "{files} file(s) have several values of category {name}"
+                )));
+            }
         }
+        self.connection()?.execute(
+            "UPDATE categories SET multiple = ?2 WHERE id = ?1",
+            params![category_id, multiple],
+        )?;
+        Ok(())
     }
 
-    fn get_value_id(&self, category_id: u32, value: &String) -> Result<String> {
-        debug!("{category_id}:{value}");
-        if let Some(ref con) = self.connection {
-            con.query_row(
+    fn get_value_id(&self, category: &String, value: &String) -> Result<String, Error> {
+        debug!("{category}:{value}");
+        let category_id = self.get_category_id(category)?;
+        self.connection()?
+            .query_row(
                 "SELECT id FROM categoryValue WHERE value = ?1 AND category_id = ?2",
                 params![value, category_id],
                 |r| r.get(0),
             )
-        } else {
-            Err(rusqlite::Error::InvalidQuery)
-        }
+            .optional()?
+            .ok_or_else(|| Error::UnknownCategoryValue(category.clone(), value.clone()))
     }
 
-    fn delete_value(&self, category_id: u32, value: &String) -> Result<usize> {
-        if let Some(ref con) = self.connection {
-            con.execute(
-                "DELETE FROM categoryValue WHERE value = ?1 AND category_id = ?2",
-                params![value, category_id],
-            )
-        } else {
-            Err(rusqlite::Error::InvalidQuery)
+    fn delete_value(&self, category_id: u32, value: &String) -> Result<usize, Error> {
+        Ok(self.connection()?.execute(
+            "DELETE FROM categoryValue WHERE value = ?1 AND category_id = ?2",
+            params![value, category_id],
+        )?)
+    }
+
+    fn insert_value(&self, category_id: u32, value: &String) -> Result<usize, Error> {
+        Ok(self.connection()?.execute(
+            "INSERT INTO categoryValue(category_id, value) VALUES (?1, ?2)",
+            params![category_id, value],
+        )?)
+    }
+
+    fn get_values(&self, category_id: u32) -> Result<Vec<String>, Error> {
+        let values = self
+            .connection()?
+            .prepare("SELECT value FROM categoryValue WHERE category_id = ?1 ORDER BY rowid")?
+            .query_map(params![category_id], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+        Ok(values)
+    }
+
+    fn count_category_files(&self, category_id: u32) -> Result<u32, Error> {
+        Ok(self.connection()?.query_row(
+            "SELECT COUNT(DISTINCT fileValues.file_id) FROM fileValues JOIN categoryValue ON fileValues.value_id = categoryValue.id WHERE categoryValue.category_id = ?1",
+            params![category_id],
+            |r| r.get(0),
+        )?)
+    }
+
+    fn count_value_files(&self, category_id: u32, value: &String) -> Result<u32, Error> {
+        Ok(self.connection()?.query_row(
+            "SELECT COUNT(DISTINCT fileValues.file_id) FROM fileValues JOIN categoryValue ON fileValues.value_id = categoryValue.id WHERE categoryValue.category_id = ?1 AND categoryValue.value = ?2",
+            params![category_id, value],
+            |r| r.get(0),
+        )?)
+    }
+
+    fn plan_tags(&self, config_tags: &[String], plan: &mut ConsistencyPlan) -> Result<(), Error> {
+        let tags = self.get_tags()?;
+        // Ancestors of a tag are kept, even if they are not listed
+        let config_tags = tags::with_ancestors(config_tags);
+
+        for tag in tags.iter() {
+            if !config_tags.contains(tag) {
+                plan.removed_tags.push(Removal {
+                    name: tag.clone(),
+                    files: self.count_tag_files(tag)?,
+                });
+            }
+        }
+
+        for tag in config_tags.iter() {
+            if !tags.contains(tag) {
+                plan.added_tags.push(tag.clone());
+            }
         }
+        Ok(())
     }
 
-    fn insert_value(&self, category_id: u32, value: &String) -> Result<usize> {
-        if let Some(ref con) = self.connection {
-            con.execute(
-                "INSERT INTO categoryValue(category_id, value) VALUES (?1, ?2)",
-                params![category_id, value],
-            )
-        } else {
-            Err(rusqlite::Error::InvalidQuery)
+    fn plan_aliases(&self, config: &Config, plan: &mut ConsistencyPlan) -> Result<(), Error> {
+        let aliases = self.get_aliases()?;
+        for (alias, tag) in aliases.iter() {
+            if config.aliases.get(alias) != Some(tag) {
+                plan.removed_aliases.push(Alias {
+                    alias: alias.clone(),
+                    tag: tag.clone(),
+                });
+            }
+        }
+        let tags = self.get_tags()?;
+        for (alias, tag) in config.aliases.iter() {
+            if aliases.get(alias) == Some(tag) {
+                continue;
+            }
+            let alias = Alias {
+                alias: alias.clone(),
+                tag: tag.clone(),
+            };
+            // A tag that became an alias is merged, its files are kept
+            if tags.contains(&alias.alias) {
+                plan.removed_tags.retain(|r| r.name != alias.alias);
+                plan.merged_tags.push(alias);
+            } else {
+                plan.added_aliases.push(alias);
+            }
+        }
+        for list in [
+            &mut plan.added_aliases,
+            &mut plan.removed_aliases,
+            &mut plan.merged_tags,
+        ] {
+            list.sort_by(|a, b| a.alias.cmp(&b.alias));
         }
+        Ok(())
     }
 
-    fn category_consistency(&self, config_cats: &Vec<Category>) -> Result<()> {
+    fn plan_categories(
+        &self,
+        config_cats: &[Category],
+        plan: &mut ConsistencyPlan,
+    ) -> Result<(), Error> {
         let categories = self.get_categories()?;
         for category in categories.iter() {
-            // Delete unused categories
             if !config_cats.iter().any(|c| c.name.eq(&category.name)) {
-                debug!("Removing category {}", category.name);
-                self.delete_category(category.id)?;
+                plan.removed_categories.push(Removal {
+                    name: category.name.clone(),
+                    files: self.count_category_files(category.id)?,
+                });
             }
         }
 
-        // Insert new categories
         for config_category in config_cats {
-            debug!("Checking category {}", config_category.name);
-            if !categories.iter().any(|c| c.name.eq(&config_category.name)) {
-                debug!("Inserting category {}", config_category.name);
-                self.insert_category(&config_category.name)?;
+            let category = match categories.iter().find(|c| c.name.eq(&config_category.name)) {
+                Some(category) => category,
+                None => {
+                    plan.added_categories.push(config_category.clone());
+                    continue;
+                }
+            };
 
-                let id: u32 = self.get_category_id(&config_category.name)?;
+            if category.multiple != config_category.multiple {
+                plan.changed_categories.push(config_category.clone());
+            }
 
-                for value in config_category.values.iter() {
-                    debug!("Inserting value {}", value);
-                    self.insert_value(id, value)?;
+            // The category exists in the database, so the values have to be compared
+            let values = self.get_values(category.id)?;
+            for val in values.iter() {
+                if !config_category.values.contains(val) {
+                    plan.removed_values.push(ValueChange {
+                        category: category.name.clone(),
+                        value: val.clone(),
+                        files: self.count_value_files(category.id, val)?,
+                    });
                 }
-            } else {
-                // Here we can be sure that the category in the config also exists
-                // in the database. We need to check if the values match
-                if let Some(ref con) = self.connection {
-                    let mut stmt = con.prepare("SELECT categoryValue.value FROM categoryValue JOIN categories ON categoryValue.category_id = categories.id WHERE categories.name = ?1")?;
-
-                    // These are the values of the current category
-                    let values: Vec<String> = stmt
-                        .query_map(params![config_category.name], |row| Ok(row.get(0)?))?
-                        .collect::<Result<Vec<String>>>()?;
-
-                    let id: u32 = self.get_category_id(&config_category.name)?;
-
-                    // Check if value needs to be deleted
-                    for val in values.iter() {
-                        if !config_category.values.iter().any(|c| c.eq(val)) {
-                            debug!("Removing value {}", val);
-                            self.delete_value(id, val)?;
-                        }
-                    }
-                    for config_val in config_category.values.iter() {
-                        if !values.iter().any(|c| c.eq(config_val)) {
-                            debug!("Inserting value {}", config_val);
-                            self.insert_value(id, config_val)?;
-                        }
-                    }
+            }
+            for config_val in config_category.values.iter() {
+                if !values.contains(config_val) {
+                    plan.added_values.push(ValueChange {
+                        category: category.name.clone(),
+                        value: config_val.clone(),
+                        files: 0,
+                    });
                 }
             }
         }
@@ -237,45 +694,283 @@ impl Database {
         Ok(())
     }
 
-    pub fn check_config_consistency(&self, config: &Config) -> Result<(), String> {
-        info!("Performing consistency check");
-        let _ = self.tag_consistency(&config.tags).map_err(|err| {
-            warn!("Updating tags failed: {err}");
-            format!("Failed to update tags: {err}").to_string()
-        });
+    /// Computes the changes needed to bring the database in line with the
+    /// config, without changing anything.
+    pub fn plan_config_consistency(&self, config: &Config) -> Result<ConsistencyPlan, Error> {
+        let mut plan = ConsistencyPlan::default();
+        self.plan_tags(&config.tags, &mut plan)
+            .map_err(|err| err.context("Failed to compare tags"))?;
+        self.plan_aliases(config, &mut plan)
+            .map_err(|err| err.context("Failed to compare aliases"))?;
+        self.plan_categories(&config.categories, &mut plan)
+            .map_err(|err| err.context("Failed to compare categories"))?;
+        debug!("Consistency plan: {plan:?}");
+        Ok(plan)
+    }
+
+    fn apply_plan(&self, plan: &ConsistencyPlan) -> Result<(), Error> {
+        for alias in plan.removed_aliases.iter() {
+            self.remove_alias(&alias.alias)?;
+        }
+        for tag in plan.added_tags.iter() {
+            debug!("Inserting tag {}", tag);
+            self.insert_tag(tag)?;
+        }
+        for merge in plan.merged_tags.iter() {
+            debug!("Merging tag {} into {}", merge.alias, merge.tag);
+            self.merge_tag(&merge.alias, &merge.tag)?;
+        }
+        for tag in plan.removed_tags.iter() {
+            debug!("Deleting tag {}", tag.name);
+            self.delete_tag(&tag.name)?;
+        }
+        for alias in plan.added_aliases.iter() {
+            self.add_alias(&alias.alias, &alias.tag)?;
+        }
+
+        for category in plan.removed_categories.iter() {
+            debug!("Removing category {}", category.name);
+            self.delete_category(self.get_category_id(&category.name)?)?;
+        }
+        for category in plan.added_categories.iter() {
+            debug!("Inserting category {}", category.name);
+            self.insert_category(&category.name)?;
+            if category.multiple {
+                self.set_multiple(&category.name, true)?;
+            }
+            let id = self.get_category_id(&category.name)?;
+            for value in category.values.iter() {
+                debug!("Inserting value {}", value);
+                self.insert_value(id, value)?;
+            }
+        }
 
-        let _ = self
-            .category_consistency(&config.categories)
+        for category in plan.changed_categories.iter() {
+            debug!(
+                "Setting multiple of {} to {}",
+                category.name, category.multiple
+            );
+            self.set_multiple(&category.name, category.multiple)?;
+        }
+
+        for value in plan.removed_values.iter() {
+            debug!("Removing value {}:{}", value.category, value.value);
+            self.delete_value(self.get_category_id(&value.category)?, &value.value)?;
+        }
+        for value in plan.added_values.iter() {
+            debug!("Inserting value {}:{}", value.category, value.value);
+            self.insert_value(self.get_category_id(&value.category)?, &value.value)?;
+        }
+        Ok(())
+    }
+
+    /// Applies all changes of the plan, or none if one of them fails.
+    pub fn apply_consistency_plan(&self, plan: &ConsistencyPlan) -> Result<(), Error> {
+        info!("Applying consistency plan");
+        let tx = self
+            .connection()?
+            .unchecked_transaction()
+            .map_err(|err| Error::from(err).context("Failed to start transaction"))?;
+        self.apply_plan(plan).map_err(|err| {
+            warn!("Updating tags and categories failed: {err}");
+            err.context("Failed to update tags and categories")
+        })?;
+        tx.commit()
+            .map_err(|err| Error::from(err).context("Failed to update tags and categories"))
+    }
+
+    /// Renames a tag in place, keeping all files associated with it. Its
+    /// descendants move along, e.g. `finance/tax` becomes `money/tax` when
+    /// `finance` is renamed to `money`. Missing ancestors of the new name are
+    /// created.
+    pub fn rename_tag(&self, old: &String, new: &String) -> Result<(), Error> {
+        info!("Renaming tag {old} to {new}");
+        tags::check(new)?;
+        if new != old && tags::is_within(new, old) {
+            return Err(Error::Invalid(format!(
+                "Tag {old} can not be moved below itself"
+            )));
+        }
+        let con = self.connection()?;
+        let id = self.get_tag_id(old)?;
+        let subtree = con
+            .prepare(&format!(
+                "SELECT id, tag FROM tags WHERE id IN ({})",
+                query::tag_subtree(1)
+            ))?
+            .query_map(params![old], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<Vec<(u32, String)>>>()?;
+
+        let tx = con
+            .unchecked_transaction()
+            .map_err(|err| Error::from(err).context("Failed to start transaction"))?;
+        for (tag_id, tag) in subtree.iter() {
+            let name = tags::renamed(tag, old, new).unwrap_or_else(|| tag.clone());
+            tx.execute(
+                "UPDATE tags SET tag = ?2 WHERE id = ?1",
+                params![tag_id, name],
+            )
             .map_err(|err| {
-                warn!("Updating cateogires failed: {err}");
-                format!("Failed to update categories: {err}").to_string()
-            });
+                conflict(err, format!("Tag {name} already exists")).context("Failed to rename tag")
+            })?;
+        }
+        let parent = self
+            .insert_ancestors(new)
+            .map_err(|err| err.context("Failed to rename tag"))?;
+        tx.execute(
+            "UPDATE tags SET parent_id = ?2 WHERE id = ?1",
+            params![id, parent],
+        )
+        .map_err(|err| Error::from(err).context("Failed to move tag"))?;
+        debug!("{} tag(s) renamed", subtree.len());
+
+        tx.commit()
+            .map_err(|err| Error::from(err).context("Failed to rename tag"))
+    }
+
+    /// Renames a category in place, keeping all values and files associated
+    /// with it.
+    pub fn rename_category(&self, old: &String, new: &String) -> Result<(), Error> {
+        info!("Renaming category {old} to {new}");
+        check_name("category", new)?;
+        match self.connection()?.execute(
+            "UPDATE categories SET name = ?2 WHERE name = ?1",
+            params![old, new],
+        ) {
+            Ok(0) => Err(Error::UnknownCategory(old.clone())),
+            Ok(updated) => {
+                debug!("{} category(s) renamed", updated);
+                Ok(())
+            }
+            Err(err) => Err(conflict(err, format!("Category {new} already exists"))
+                .context("Failed to rename category")),
+        }
+    }
+
+    /// Renames a value of a category in place, keeping all files associated
+    /// with it.
+    pub fn rename_category_value(
+        &self,
+        category: &String,
+        old: &String,
+        new: &String,
+    ) -> Result<(), Error> {
+        info!("Renaming value {category}:{old} to {new}");
+        check_name("value", new)?;
+        let con = self.connection()?;
+        let category_id = self.get_category_id(category)?;
+        let old_id = self.get_value_id(category, old)?;
+
+        let tx = con
+            .unchecked_transaction()
+            .map_err(|err| Error::from(err).context("Failed to start transaction"))?;
+        // The id of a value is generated from its name, so the references
+        // are briefly dangling until fileValues is updated too
+        tx.execute_batch("PRAGMA defer_foreign_keys = ON")
+            .map_err(|err| Error::from(err).context("Failed to rename value"))?;
+        tx.execute(
+            "UPDATE categoryValue SET value = ?3 WHERE category_id = ?1 AND value = ?2",
+            params![category_id, old, new],
+        )
+        .map_err(|err| {
+            conflict(err, format!("Value {category}:{new} already exists"))
+                .context("Failed to rename value")
+        })?;
+        let new_id = self.get_value_id(category, new)?;
+        tx.execute(
+            "UPDATE fileValues SET value_id = ?2 WHERE value_id = ?1",
+            params![old_id, new_id],
+        )
+        .map_err(|err| Error::from(err).context("Failed to update fileValues"))?;
+
+        tx.commit()
+            .map_err(|err| Error::from(err).context("Failed to rename value"))
+    }
+
+    /// Brings the database in line with the config, but never removes tags,
+    /// categories or values that are still used by files. Those removals have
+    /// to be confirmed through `apply_consistency_plan`.
+    pub fn check_config_consistency(&self, config: &Config) -> Result<(), Error> {
+        info!("Performing consistency check");
+        let plan = self.plan_config_consistency(config)?;
+        if plan.is_destructive() {
+            warn!("Not removing tags or values still in use: {plan:?}");
+        }
+        self.apply_consistency_plan(&plan.safe())
+    }
+
+    /// Adds the tags, categories and values of the config missing in the
+    /// vault, e.g. from a config written before the vault stored them.
+    /// Nothing is removed.
+    pub fn import_taxonomy(&self, config: &Config) -> Result<(), Error> {
+        let mut plan = self.plan_config_consistency(config)?;
+        plan.removed_tags.clear();
+        plan.removed_aliases.clear();
+        plan.merged_tags.clear();
+        plan.removed_categories.clear();
+        plan.removed_values.clear();
+        self.apply_consistency_plan(&plan)
+    }
 
+    /// Replaces the tags and categories of the config with the ones stored in
+    /// the vault, which are authoritative.
+    pub fn load_taxonomy(&self, config: &mut Config) -> Result<(), Error> {
+        let mut tags = self
+            .get_tags()
+            .map_err(|err| err.context("Failed to load tags"))?;
+        tags::sort(&mut tags);
+        let categories = self
+            .get_categories()?
+            .into_iter()
+            .map(|category| {
+                Ok(Category {
+                    values: self.get_values(category.id)?,
+                    name: category.name,
+                    multiple: category.multiple,
+                })
+            })
+            .collect::<Result<Vec<Category>, Error>>()
+            .map_err(|err| err.context("Failed to load categories"))?;
+        config.aliases = self
+            .get_aliases()
+            .map_err(|err| err.context("Failed to load aliases"))?;
+        config.tags = tags;
+        config.categories = categories;
         Ok(())
     }
 
-    fn get_file_id(&self, relative_path: &String) -> Result<u32> {
-        if let Some(ref con) = self.connection {
-            con.query_row(
+    fn get_file_id(&self, relative_path: &String) -> Result<u32, Error> {
+        self.connection()?
+            .query_row(
                 "SELECT id FROM files WHERE path = ?1",
                 params![relative_path],
                 |r| r.get(0),
             )
-        } else {
-            Err(rusqlite::Error::InvalidQuery)
-        }
+            .optional()?
+            .ok_or_else(|| Error::UnknownFile(relative_path.clone()))
     }
 
-    pub fn store_file(&self, relative_path: &String, name: &String) -> Result<(), String> {
-        if let Some(ref con) = self.connection {
-            match con.execute(
-                "INSERT INTO files(path, name) VALUES (?1, ?2)",
-                params![relative_path, name],
-            ) {
-                Ok(updated) => info!("{} file(s) inserted", updated),
-                Err(err) => return Err(format!("Failed to insert file: {err}").to_string()),
+    pub fn store_file(
+        &self,
+        relative_path: &String,
+        name: &String,
+        date: Option<&String>,
+    ) -> Result<(), Error> {
+        if let Some(date) = date {
+            check_date(date)?;
+        }
+        match self.connection()?.execute(
+            "INSERT INTO files(path, name, date, imported_at) VALUES (?1, ?2, ?3, ?4)",
+            params![relative_path, name, date, chrono::Utc::now().timestamp()],
+        ) {
+            Ok(updated) => info!("{} file(s) inserted", updated),
+            Err(err) => {
+                return Err(
+                    conflict(err, format!("A file is already stored at {relative_path}"))
+                        .context("Failed to insert file"),
+                )
             }
-        };
+        }
 
         Ok(())
     }
@@ -284,30 +979,23 @@ impl Database {
         &self,
         relative_path: &String,
         tag: &String,
-    ) -> Result<(), String> {
-        let file_id = match self.get_file_id(relative_path) {
-            Ok(id) => id,
-            Err(err) => {
-                warn!("Failed to get file id: {err}");
-                return Err(format!("Failed to associate file with tag: {err}").to_string());
-            }
-        };
-        let tag_id = match self.get_tag_id(tag) {
-            Ok(id) => id,
-            Err(err) => {
-                warn!("Failed to get tag id: {err}");
-                return Err(format!("Failed to find tag in database: {err}").to_string());
-            }
-        };
+    ) -> Result<(), Error> {
+        let file_id = self.get_file_id(relative_path).map_err(|err| {
+            warn!("Failed to get file id: {err}");
+            err
+        })?;
+        // Aliases stand for their tag
+        let tag_id = self.get_canonical_tag_id(tag).map_err(|err| {
+            warn!("Failed to get tag id: {err}");
+            err
+        })?;
 
-        if let Some(ref con) = self.connection {
-            match con.execute(
-                "INSERT INTO fileTags(file_id, tag_id) Values(?1, ?2)",
-                params![file_id, tag_id],
-            ) {
-                Ok(updated) => debug!("{} fileTag(s) inserted", updated),
-                Err(err) => return Err(format!("Failed to insert fileTag: {err}").to_string()),
-            }
+        match self.connection()?.execute(
+            "INSERT INTO fileTags(file_id, tag_id) SELECT ?1, ?2 WHERE NOT EXISTS (SELECT 1 FROM fileTags WHERE file_id = ?1 AND tag_id = ?2)",
+            params![file_id, tag_id],
+        ) {
+            Ok(updated) => debug!("{} fileTag(s) inserted", updated),
+            Err(err) => return Err(Error::from(err).context("Failed to insert fileTag")),
         }
         Ok(())
     }
@@ -317,110 +1005,647 @@ impl Database {
         relative_path: &String,
         category: &String,
         value: &String,
-    ) -> Result<(), String> {
+    ) -> Result<(), Error> {
         debug!("Inserting {category}:{value} for {relative_path}");
-        let file_id = match self.get_file_id(relative_path) {
-            Ok(id) => id,
-            Err(err) => {
-                warn!("Failed to get file id: {err}");
-                return Err(format!("Failed to associate file with value: {err}").to_string());
-            }
-        };
+        let file_id = self.get_file_id(relative_path).map_err(|err| {
+            warn!("Failed to get file id: {err}");
+            err
+        })?;
+        let value_id = self.get_value_id(category, value).map_err(|err| {
+            warn!("Failed to get value id: {err}");
+            err
+        })?;
 
-        let category_id = match self.get_category_id(category) {
-            Ok(id) => id,
-            Err(err) => {
-                warn!("Failed to get category id: {err}");
-                return Err(format!("Failed to find category in database: {err}").to_string());
-            }
+        let current = self.get_file_values(file_id)?.remove(category);
+        if current
+            .as_ref()
+            .is_some_and(|values| values.contains(value))
+        {
+            return Ok(());
+        }
+        if current.is_some() && !self.is_multiple(self.get_category_id(category)?)? {
+            return Err(Error::Invalid(format!(
+                "Category {category} takes a single value"
+            )));
+        }
+
+        match self.connection()?.execute(
+            "INSERT INTO fileValues(file_id, value_id) Values(?1, ?2)",
+            params![file_id, value_id],
+        ) {
+            Ok(updated) => debug!("{} fileValue(s) inserted", updated),
+            Err(err) => return Err(Error::from(err).context("Failed to insert fileValue")),
+        }
+
+        Ok(())
+    }
+
+    /// Removes a file from the database and its folder from the vault. The
+    /// folder is first moved aside, so a failure on either side can be undone
+    /// before the deletion is committed.
+    pub fn delete_file(&self, vault: &Path, relative_path: &String) -> Result<(), Error> {
+        info!("Deleting file {relative_path}");
+        let con = self.connection()?;
+        let layout: LayoutKind = match con
+            .query_row(
+                "SELECT layout FROM files WHERE path = ?1",
+                params![relative_path],
+                |r| r.get(0),
+            )
+            .optional()
+        {
+            Ok(Some(layout)) => layout,
+            Ok(None) => return Err(Error::UnknownFile(relative_path.clone())),
+            Err(err) => return Err(Error::from(err).context("Failed to delete file")),
         };
+        let tx = con
+            .unchecked_transaction()
+            .map_err(|err| Error::from(err).context("Failed to start transaction"))?;
 
-        let value_id = match self.get_value_id(category_id, value) {
-            Ok(id) => id,
-            Err(err) => {
-                warn!("Failed to get value id: {err}");
-                return Err(format!("Failed to find value in database: {err}").to_string());
+        match tx.execute("DELETE FROM files WHERE path = ?1", params![relative_path]) {
+            Ok(0) => return Err(Error::UnknownFile(relative_path.clone())),
+            Ok(updated) => debug!("{} file(s) deleted", updated),
+            Err(err) => return Err(Error::from(err).context("Failed to delete file")),
+        }
+
+        // The folder of the file, or the file itself if it has no folder
+        let stored = vault.join(relative_path);
+        let trash = match stored.file_name() {
+            Some(name) => stored.with_file_name(format!(".{}.deleted", name.to_string_lossy())),
+            None => return Err(Error::Invalid(format!("Invalid file path {relative_path}"))),
+        };
+        let moved = match fs::rename(&stored, &trash) {
+            Ok(()) => true,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                warn!("{:?} does not exist anymore", stored);
+                false
             }
+            Err(err) => return Err(Error::from(err).context("Failed to remove folder")),
         };
 
-        if let Some(ref con) = self.connection {
-            match con.execute(
-                "INSERT INTO fileValues(file_id, value_id) Values(?1, ?2)",
-                params![file_id, value_id],
-            ) {
-                Ok(updated) => debug!("{} fileValue(s) inserted", updated),
-                Err(err) => return Err(format!("Faield to insert fileValue: {err}").to_string()),
+        if let Err(err) = tx.commit() {
+            if moved {
+                if let Err(err) = fs::rename(&trash, &stored) {
+                    warn!("Restoring {:?} failed: {err}", stored);
+                }
             }
+            return Err(Error::from(err).context("Failed to delete file"));
         }
 
+        if moved {
+            let removed = match layout {
+                LayoutKind::Template => fs::remove_file(&trash),
+                _ => fs::remove_dir_all(&trash),
+            };
+            if let Err(err) = removed {
+                warn!("Removing {:?} failed: {err}", trash);
+            }
+            layout::remove_empty_parents(vault, &trash);
+        }
         Ok(())
     }
 
-    fn create_tables(&self) -> Result<()> {
-        let qry = "
-PRAGMA foreign_keys = ON;
-
-CREATE TABLE IF NOT EXISTS files (
-  id INTEGER PRIMARY KEY,
-  path TEXT NOT NULL UNIQUE,
-  name TEXT NOT NULL
-);
-
-CREATE TABLE IF NOT EXISTS tags (
-  id INTEGER PRIMARY KEY,
-  tag TEXT NOT NULL UNIQUE
-);
-
-CREATE TABLE IF NOT EXISTS fileTags (
-  file_id INTEGER,
-  tag_id INTEGER,
-  CONSTRAINT fk_file FOREIGN KEY (file_id) REFERENCES files(id) ON UPDATE CASCADE ON DELETE CASCADE,
-  CONSTRAINT fk_tag FOREIGN KEY (tag_id) REFERENCES tags(id) ON UPDATE CASCADE ON DELETE CASCADE
-);
-
-CREATE TABLE IF NOT EXISTS categories (
-  id INTEGER PRIMARY KEY,
-  name TEXT NOT NULL UNIQUE
-);
-
-CREATE TABLE IF NOT EXISTS categoryValue (
-  category_id INTEGER,
-  value TEXT NOT NULL,
-  id TEXT GENERATED ALWAYS AS (concat(value, category_id)) VIRTUAL UNIQUE,
-  CONSTRAINT fk_category FOREIGN KEY (category_id) REFERENCES categories(id) ON UPDATE CASCADE ON DELETE CASCADE,
-  CONSTRAINT cv PRIMARY KEY (category_id, value)
-);
-
-
-CREATE TABLE IF NOT EXISTS fileValues (
-  file_id INTEGER,
-  value_id TEXT,
-  CONSTRAINT fk_file FOREIGN KEY (file_id) REFERENCES files(id) ON UPDATE CASCADE ON DELETE CASCADE,
-  CONSTRAINT fk_value FOREIGN KEY (value_id) REFERENCES categoryValue(id) ON UPDATE CASCADE ON DELETE CASCADE
-);";
-        if let Some(ref con) = self.connection {
-            con.execute_batch(qry)
-        } else {
-            Err(rusqlite::Error::InvalidQuery)
+    /// Stores an imported file together with its metadata and extracted
+    /// content. Either everything is stored or nothing.
+    pub fn store_import(
+        &self,
+        relative_path: &String,
+        name: &String,
+        layout: LayoutKind,
+        metadata: &FileMetadata,
+        content: Option<&String>,
+        hash: Option<&String>,
+    ) -> Result<(), Error> {
+        let tx = self
+            .connection()?
+            .unchecked_transaction()
+            .map_err(|err| Error::from(err).context("Failed to start transaction"))?;
+
+        self.store_file(relative_path, name, metadata.date.as_ref())?;
+
+        if layout != LayoutKind::default() {
+            self.set_file_layout(relative_path, layout)
+                .map_err(|err| err.context("Failed to store layout"))?;
         }
-    }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        if let Some(hash) = hash {
+            self.set_file_hash(relative_path, hash)?;
+        }
 
-    fn create_db() -> Database {
-        let con = Connection::open_in_memory().expect("Database in memory failed");
-        let db = Database {
-            connection: Some(con),
-        };
-        db.create_tables().expect("Database creation failed");
-        db
+        for tag in metadata.tags.iter() {
+            self.associate_tag_with_file(relative_path, tag)?;
+        }
+
+        for (category, values) in metadata.categories.iter() {
+            for value in values.iter() {
+                self.associate_value_with_file(relative_path, category, value)?;
+            }
+        }
+
+        if let Some(content) = content {
+            self.store_content(relative_path, content)?;
+        }
+
+        tx.commit()
+            .map_err(|err| Error::from(err).context("Failed to import file"))
     }
 
-    #[test]
-    fn tag_insert() {
+    fn get_file_tags(&self, file_id: u32) -> Result<Vec<String>, Error> {
+        let tags = self
+            .connection()?
+            .prepare("SELECT tags.tag FROM fileTags JOIN tags ON fileTags.tag_id = tags.id WHERE fileTags.file_id = ?1 ORDER BY tags.tag")?
+            .query_map(params![file_id], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+        Ok(tags)
+    }
+
+    fn get_file_values(&self, file_id: u32) -> Result<HashMap<String, Vec<String>>, Error> {
+        let rows = self
+            .connection()?
+            .prepare("SELECT categories.name, categoryValue.value FROM fileValues JOIN categoryValue ON fileValues.value_id = categoryValue.id JOIN categories ON categoryValue.category_id = categories.id WHERE fileValues.file_id = ?1 ORDER BY categoryValue.value")?
+            .query_map(params![file_id], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<Vec<(String, String)>>>()?;
+        let mut values: HashMap<String, Vec<String>> = HashMap::new();
+        for (category, value) in rows {
+            values.entry(category).or_default().push(value);
+        }
+        Ok(values)
+    }
+
+    fn get_file_entry(&self, file_id: u32) -> Result<FileEntry, Error> {
+        let (path, name, date, imported_at, layout) = self.connection()?.query_row(
+            "SELECT path, name, date, imported_at, layout FROM files WHERE id = ?1",
+            params![file_id],
+            |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                ))
+            },
+        )?;
+        Ok(FileEntry {
+            path,
+            name,
+            tags: self.get_file_tags(file_id)?,
+            categories: self.get_file_values(file_id)?,
+            date,
+            imported_at,
+            layout,
+        })
+    }
+
+    pub fn get_file(&self, relative_path: &String) -> Result<FileEntry, Error> {
+        self.get_file_id(relative_path)
+            .and_then(|id| self.get_file_entry(id))
+            .map_err(|err| err.context(&format!("Failed to find file {relative_path}")))
+    }
+
+    fn set_file_layout(&self, relative_path: &String, layout: LayoutKind) -> Result<usize, Error> {
+        Ok(self.connection()?.execute(
+            "UPDATE files SET layout = ?2 WHERE path = ?1",
+            params![relative_path, layout],
+        )?)
+    }
+
+    pub fn set_file_hash(&self, relative_path: &String, hash: &String) -> Result<(), Error> {
+        match self.connection()?.execute(
+            "UPDATE files SET hash = ?2 WHERE path = ?1",
+            params![relative_path, hash],
+        ) {
+            Ok(1) => Ok(()),
+            Ok(_) => Err(Error::UnknownFile(relative_path.clone())),
+            Err(err) => Err(Error::from(err).context("Failed to store hash")),
+        }
+    }
+
+    /// The first file stored with the given content hash, if any.
+    pub fn get_file_by_hash(&self, hash: &String) -> Result<Option<FileEntry>, Error> {
+        let id: Option<u32> = self
+            .connection()?
+            .query_row(
+                "SELECT id FROM files WHERE hash = ?1 ORDER BY id LIMIT 1",
+                params![hash],
+                |r| r.get(0),
+            )
+            .optional()
+            .map_err(|err| Error::from(err).context("Failed to find file by hash"))?;
+        match id {
+            Some(id) => self
+                .get_file_entry(id)
+                .map(Some)
+                .map_err(|err| err.context("Failed to find file by hash")),
+            None => Ok(None),
+        }
+    }
+
+    /// The files without a content hash, i.e. imported before hashes were
+    /// stored.
+    pub fn files_without_hash(&self) -> Result<Vec<FileEntry>, Error> {
+        let ids: Vec<u32> = self
+            .connection()?
+            .prepare("SELECT id FROM files WHERE hash IS NULL ORDER BY id")
+            .and_then(|mut stmt| {
+                stmt.query_map([], |row| row.get(0))?
+                    .collect::<rusqlite::Result<Vec<u32>>>()
+            })
+            .map_err(|err| Error::from(err).context("Failed to get files without hash"))?;
+        ids.into_iter()
+            .map(|id| self.get_file_entry(id))
+            .collect::<Result<Vec<FileEntry>, Error>>()
+            .map_err(|err| err.context("Failed to get files without hash"))
+    }
+
+    /// The stored content hashes by file path.
+    pub fn file_hashes(&self) -> Result<HashMap<String, String>, Error> {
+        self.connection()?
+            .prepare("SELECT path, hash FROM files WHERE hash IS NOT NULL")
+            .and_then(|mut stmt| {
+                stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+                    .collect::<rusqlite::Result<HashMap<String, String>>>()
+            })
+            .map_err(|err| Error::from(err).context("Failed to get hashes"))
+    }
+
+    /// Removes a file from the database only, e.g. after it vanished from the
+    /// vault.
+    pub fn forget_file(&self, relative_path: &String) -> Result<(), Error> {
+        match self
+            .connection()?
+            .execute("DELETE FROM files WHERE path = ?1", params![relative_path])
+        {
+            Ok(0) => Err(Error::UnknownFile(relative_path.clone())),
+            Ok(_) => {
+                info!("Removed entry of {relative_path}");
+                Ok(())
+            }
+            Err(err) => Err(Error::from(err).context("Failed to remove file entry")),
+        }
+    }
+
+    /// Number of `fileTags` and `fileValues` rows pointing to files, tags or
+    /// values that do not exist.
+    pub fn count_dangling(&self) -> Result<(usize, usize), Error> {
+        let con = self.connection()?;
+        let tags = con.query_row(
+            &format!("SELECT COUNT(*) FROM fileTags WHERE {DANGLING_TAGS}"),
+            [],
+            |r| r.get(0),
+        );
+        let values = con.query_row(
+            &format!("SELECT COUNT(*) FROM fileValues WHERE {DANGLING_VALUES}"),
+            [],
+            |r| r.get(0),
+        );
+        match (tags, values) {
+            (Ok(tags), Ok(values)) => Ok((tags, values)),
+            (Err(err), _) | (_, Err(err)) => {
+                Err(Error::from(err).context("Failed to count dangling rows"))
+            }
+        }
+    }
+
+    /// Deletes the rows counted by `count_dangling`.
+    pub fn remove_dangling(&self) -> Result<usize, Error> {
+        let con = self.connection()?;
+        let tags = con.execute(&format!("DELETE FROM fileTags WHERE {DANGLING_TAGS}"), []);
+        let values = con.execute(
+            &format!("DELETE FROM fileValues WHERE {DANGLING_VALUES}"),
+            [],
+        );
+        match (tags, values) {
+            (Ok(tags), Ok(values)) => {
+                info!("Removed {tags} dangling fileTags and {values} fileValues");
+                Ok(tags + values)
+            }
+            (Err(err), _) | (_, Err(err)) => {
+                Err(Error::from(err).context("Failed to remove dangling rows"))
+            }
+        }
+    }
+
+    fn get_duplicate_ids(&self) -> Result<Vec<Vec<u32>>, Error> {
+        let mut groups: Vec<Vec<u32>> = Vec::new();
+        let mut last_hash: Option<String> = None;
+        let rows = self
+            .connection()?
+            .prepare("SELECT hash, id FROM files WHERE hash IN (SELECT hash FROM files WHERE hash IS NOT NULL GROUP BY hash HAVING COUNT(*) > 1) ORDER BY hash, id")?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<Vec<(String, u32)>>>()?;
+        for (hash, id) in rows {
+            match groups.last_mut() {
+                Some(group) if last_hash.as_ref() == Some(&hash) => group.push(id),
+                _ => groups.push(vec![id]),
+            }
+            last_hash = Some(hash);
+        }
+        Ok(groups)
+    }
+
+    /// Groups of files with the same content, each ordered by import.
+    pub fn find_duplicates(&self) -> Result<Vec<Vec<FileEntry>>, Error> {
+        self.get_duplicate_ids()
+            .and_then(|groups| {
+                groups
+                    .into_iter()
+                    .map(|ids| ids.into_iter().map(|id| self.get_file_entry(id)).collect())
+                    .collect()
+            })
+            .map_err(|err| {
+                warn!("Finding duplicates failed: {err}");
+                err.context("Failed to find duplicates")
+            })
+    }
+
+    fn query_files(
+        &self,
+        condition: Option<&Expr>,
+        sort: SortOrder,
+    ) -> Result<Vec<FileEntry>, Error> {
+        let mut parameters: Vec<String> = Vec::new();
+        let mut qry = "SELECT id FROM files".to_string();
+        if let Some(expr) = condition {
+            qry.push_str(" WHERE ");
+            qry.push_str(&expr.compile(&mut parameters));
+        }
+        qry.push_str(" ORDER BY ");
+        qry.push_str(sort.sql());
+        debug!("Search query: {qry}");
+
+        let ids: Vec<u32> = self
+            .connection()?
+            .prepare(&qry)?
+            .query_map(params_from_iter(parameters), |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<u32>>>()?;
+
+        ids.into_iter().map(|id| self.get_file_entry(id)).collect()
+    }
+
+    pub fn search(&self, query: &SearchQuery) -> Result<Vec<FileEntry>, Error> {
+        info!("Searching files: {query:?}");
+        for date in query.date_from.iter().chain(query.date_to.iter()) {
+            check_date(date)?;
+        }
+        self.query_files(query.to_expr().as_ref(), query.sort)
+            .map_err(|err| {
+                warn!("Searching files failed: {err}");
+                err.context("Failed to search files")
+            })
+    }
+
+    pub fn query(&self, input: &str, sort: SortOrder) -> Result<Vec<FileEntry>, Error> {
+        info!("Querying files: {input}");
+        let expr = if input.trim().is_empty() {
+            None
+        } else {
+            Some(
+                query::parse(input)
+                    .map_err(|err| Error::Invalid(format!("Invalid query: {err}")))?,
+            )
+        };
+        self.query_files(expr.as_ref(), sort).map_err(|err| {
+            warn!("Querying files failed: {err}");
+            err.context("Failed to query files")
+        })
+    }
+
+    pub fn store_content(&self, relative_path: &String, content: &String) -> Result<(), Error> {
+        let file_id = self.get_file_id(relative_path).map_err(|err| {
+            warn!("Failed to get file id: {err}");
+            err
+        })?;
+
+        match self.connection()?.execute(
+            "INSERT INTO fileContents(rowid, content) VALUES (?1, ?2)",
+            params![file_id, content],
+        ) {
+            Ok(updated) => debug!("{} fileContent(s) inserted", updated),
+            Err(err) => return Err(Error::from(err).context("Failed to insert file content")),
+        }
+        Ok(())
+    }
+
+    fn query_content(&self, text: &str) -> Result<Vec<TextHit>, Error> {
+        let hits: Vec<(u32, String, f64)> = self
+            .connection()?
+            .prepare("SELECT rowid, snippet(fileContents, 0, '<mark>', '</mark>', '...', 16), rank FROM fileContents WHERE fileContents MATCH ?1 ORDER BY rank")?
+            .query_map(params![text], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+            .collect::<rusqlite::Result<Vec<(u32, String, f64)>>>()
+            // A malformed full-text query is only noticed when it runs
+            .map_err(|err| Error::Invalid(err.to_string()))?;
+
+        hits.into_iter()
+            .map(|(id, snippet, rank)| {
+                Ok(TextHit {
+                    file: self.get_file_entry(id)?,
+                    snippet,
+                    rank,
+                })
+            })
+            .collect()
+    }
+
+    pub fn full_text_search(&self, text: &str) -> Result<Vec<TextHit>, Error> {
+        info!("Full-text search: {text}");
+        self.query_content(text).map_err(|err| {
+            warn!("Full-text search failed: {err}");
+            err.context("Failed to search file contents")
+        })
+    }
+
+    fn remove_tag_from_file(&self, file_id: u32, tag: &String) -> Result<usize, Error> {
+        Ok(self.connection()?.execute(
+            "DELETE FROM fileTags WHERE file_id = ?1 AND tag_id = (SELECT id FROM tags WHERE tag = ?2)",
+            params![file_id, tag],
+        )?)
+    }
+
+    fn remove_value_from_file(
+        &self,
+        file_id: u32,
+        category: &String,
+        value: &String,
+    ) -> Result<usize, Error> {
+        Ok(self.connection()?.execute(
+            "DELETE FROM fileValues WHERE file_id = ?1 AND value_id = (SELECT categoryValue.id FROM categoryValue JOIN categories ON categoryValue.category_id = categories.id WHERE categories.name = ?2 AND categoryValue.value = ?3)",
+            params![file_id, category, value],
+        )?)
+    }
+
+    /// Replaces the tags and category values of a file with the given ones.
+    /// Only the differences are written, either all of them or none.
+    pub fn update_file_metadata(
+        &self,
+        relative_path: &String,
+        tags: &[String],
+        categories: &HashMap<String, Vec<String>>,
+    ) -> Result<(), Error> {
+        info!("Updating metadata of {relative_path}");
+        let con = self.connection()?;
+        let file_id = self.get_file_id(relative_path).map_err(|err| {
+            warn!("Failed to get file id: {err}");
+            err
+        })?;
+        let tx = con
+            .unchecked_transaction()
+            .map_err(|err| Error::from(err).context("Failed to start transaction"))?;
+
+        let current_tags = self
+            .get_file_tags(file_id)
+            .map_err(|err| err.context("Failed to get tags of file"))?;
+        let current_values = self
+            .get_file_values(file_id)
+            .map_err(|err| err.context("Failed to get values of file"))?;
+        let mut tags = tags
+            .iter()
+            .map(|tag| self.resolve_tag(tag))
+            .collect::<Result<Vec<String>, Error>>()?;
+        // A tag given twice, or along with one of its aliases, is added once
+        let mut seen = Vec::new();
+        tags.retain(|tag| {
+            let first = !seen.contains(tag);
+            seen.push(tag.clone());
+            first
+        });
+
+        for tag in current_tags.iter().filter(|t| !tags.contains(t)) {
+            debug!("Removing tag {tag} from {relative_path}");
+            self.remove_tag_from_file(file_id, tag)
+                .map_err(|err| err.context("Failed to remove tag"))?;
+        }
+        for tag in tags.iter().filter(|t| !current_tags.contains(t)) {
+            self.associate_tag_with_file(relative_path, tag)?;
+        }
+
+        // Removing first frees single value categories for the new value
+        let has_value = |values: &HashMap<String, Vec<String>>, category, value| {
+            values.get(category).is_some_and(|v| v.contains(value))
+        };
+        for (category, values) in current_values.iter() {
+            for value in values.iter() {
+                if !has_value(categories, category, value) {
+                    debug!("Removing {category}:{value} from {relative_path}");
+                    self.remove_value_from_file(file_id, category, value)
+                        .map_err(|err| err.context("Failed to remove value"))?;
+                }
+            }
+        }
+        for (category, values) in categories.iter() {
+            for value in values.iter() {
+                if !has_value(&current_values, category, value) {
+                    self.associate_value_with_file(relative_path, category, value)?;
+                }
+            }
+        }
+
+        tx.commit()
+            .map_err(|err| Error::from(err).context("Failed to update file metadata"))
+    }
+
+    /// Brings the schema up to date, backing up the database at `path` first
+    /// if anything has to be changed.
+    fn migrate(&self, path: Option<&Path>) -> Result<(), Error> {
+        let con = self.connection()?;
+        con.execute_batch("PRAGMA foreign_keys = ON;")?;
+        Ok(migration::migrate(con, path)?)
+    }
+}
+
+/// A violated unique constraint means the name or path is taken, anything else
+/// is a database error.
+fn conflict(err: rusqlite::Error, message: String) -> Error {
+    match err {
+        rusqlite::Error::SqliteFailure(ref failure, _)
+            if failure.extended_code == rusqlite::ffi::SQLITE_CONSTRAINT_UNIQUE
+                || failure.extended_code == rusqlite::ffi::SQLITE_CONSTRAINT_PRIMARYKEY =>
+        {
+            Error::Conflict(message)
+        }
+        _ => Error::from(err),
+    }
+}
+
+/// A new name has to contain more than whitespace.
+fn check_name(kind: &str, name: &str) -> Result<(), Error> {
+    if name.trim().is_empty() {
+        return Err(Error::Invalid(format!("A {kind} needs a name")));
+    }
+    Ok(())
+}
+
+fn check_date(date: &String) -> Result<(), Error> {
+    match chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d") {
+        Ok(_) => Ok(()),
+        Err(err) => Err(Error::Invalid(format!(
+            "Invalid date {date}, expected YYYY-MM-DD: {err}"
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    fn create_db() -> Database {
+        let con = Connection::open_in_memory().expect("Database in memory failed");
+        let db = Database {
+            connection: Some(con),
+        };
+        db.migrate(None).expect("Database creation failed");
+        db
+    }
+
+    fn create_file(db: &Database, path: &str, tags: &[&str], values: &[(&str, &str)]) {
+        db.store_file(&path.to_string(), &format!("{path}.pdf"), None)
+            .expect("Failed to store file");
+        for tag in tags {
+            db.associate_tag_with_file(&path.to_string(), &tag.to_string())
+                .expect("Failed to associate tag");
+        }
+        for (category, value) in values {
+            db.associate_value_with_file(
+                &path.to_string(),
+                &category.to_string(),
+                &value.to_string(),
+            )
+            .expect("Failed to associate value");
+        }
+    }
+
+    fn create_search_db() -> Database {
+        let db = create_db();
+        for tag in ["taxes", "draft"] {
+            db.insert_tag(&tag.to_string())
+                .expect("Failed to insert tag");
+        }
+        db.insert_category(&"person".to_string())
+            .expect("Failed to insert category");
+        let id = db
+            .get_category_id(&"person".to_string())
+            .expect("Failed to get category id");
+        for value in ["Olivia", "Liam"] {
+            db.insert_value(id, &value.to_string())
+                .expect("Failed to insert value");
+        }
+        create_file(&db, "aaaaaaa", &["taxes"], &[("person", "Olivia")]);
+        create_file(&db, "bbbbbbb", &["taxes", "draft"], &[("person", "Liam")]);
+        create_file(&db, "ccccccc", &[], &[]);
+        db
+    }
+
+    fn search_paths(db: &Database, query: &SearchQuery) -> Vec<String> {
+        db.search(query)
+            .expect("Search failed")
+            .into_iter()
+            .map(|f| f.path)
+            .collect()
+    }
+
+    #[test]
+    fn tag_insert() {
         let db = create_db();
         assert_eq!(Vec::<String>::new(), db.get_tags().unwrap());
         db.insert_tag(&"test".to_string())
@@ -489,4 +1714,790 @@ mod tests {
         db.insert_category(&"test".to_string())
             .expect("Failed to insert category");
     }
+
+    #[test]
+    fn duplicates() {
+        let db = create_search_db();
+        for (path, hash) in [("aaaaaaa", "1"), ("bbbbbbb", "2"), ("ccccccc", "1")] {
+            db.set_file_hash(&path.to_string(), &hash.to_string())
+                .expect("Failed to store hash");
+        }
+        create_file(&db, "ddddddd", &[], &[]);
+        let unhashed: Vec<String> = db
+            .files_without_hash()
+            .unwrap()
+            .into_iter()
+            .map(|f| f.path)
+            .collect();
+        assert_eq!(vec!["ddddddd"], unhashed);
+        assert!(db
+            .set_file_hash(&"missing".to_string(), &"1".to_string())
+            .is_err());
+
+        let existing = db.get_file_by_hash(&"1".to_string()).unwrap().unwrap();
+        assert_eq!("aaaaaaa", existing.path);
+        assert_eq!(None, db.get_file_by_hash(&"3".to_string()).unwrap());
+
+        let groups: Vec<Vec<String>> = db
+            .find_duplicates()
+            .unwrap()
+            .into_iter()
+            .map(|group| group.into_iter().map(|f| f.path).collect())
+            .collect();
+        assert_eq!(vec![vec!["aaaaaaa", "ccccccc"]], groups);
+    }
+
+    #[test]
+    fn search_all() {
+        let db = create_search_db();
+        assert_eq!(
+            vec!["aaaaaaa", "bbbbbbb", "ccccccc"],
+            search_paths(&db, &SearchQuery::default())
+        );
+    }
+
+    #[test]
+    fn search_tags() {
+        let db = create_search_db();
+        let query = SearchQuery {
+            tags: vec!["taxes".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(vec!["aaaaaaa", "bbbbbbb"], search_paths(&db, &query));
+
+        let query = SearchQuery {
+            tags: vec!["taxes".to_string()],
+            excluded_tags: vec!["draft".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(vec!["aaaaaaa"], search_paths(&db, &query));
+    }
+
+    #[test]
+    fn long_query_stays_flat() {
+        let query = SearchQuery {
+            tags: vec!["taxes".to_string(); 20_000],
+            categories: HashMap::from([("person".to_string(), vec!["Liam".to_string(); 20_000])]),
+            ..Default::default()
+        };
+        let mut params = Vec::new();
+        query.to_expr().unwrap().compile(&mut params);
+        assert_eq!(60_000, params.len());
+    }
+
+    #[test]
+    fn search_values() {
+        let db = create_search_db();
+        let query = SearchQuery {
+            categories: HashMap::from([("person".to_string(), vec!["Liam".to_string()])]),
+            ..Default::default()
+        };
+        let result = db.search(&query).expect("Search failed");
+        assert_eq!(
+            vec![FileEntry {
+                path: "bbbbbbb".to_string(),
+                name: "bbbbbbb.pdf".to_string(),
+                tags: vec!["draft".to_string(), "taxes".to_string()],
+                categories: HashMap::from([("person".to_string(), vec!["Liam".to_string()])]),
+                date: None,
+                imported_at: result[0].imported_at,
+                layout: LayoutKind::Random,
+            }],
+            result
+        );
+    }
+
+    #[test]
+    fn query_language() {
+        let db = create_search_db();
+        let paths = |input: &str| -> Vec<String> {
+            db.query(input, SortOrder::Name)
+                .expect("Query failed")
+                .into_iter()
+                .map(|f| f.path)
+                .collect()
+        };
+        assert_eq!(vec!["aaaaaaa", "bbbbbbb", "ccccccc"], paths(""));
+        assert_eq!(vec!["aaaaaaa"], paths("tag:taxes AND NOT tag:draft"));
+        assert_eq!(
+            vec!["aaaaaaa", "bbbbbbb"],
+            paths("person=Olivia OR (person=Liam tag:draft)")
+        );
+        assert_eq!(vec!["ccccccc"], paths("name:c*.pdf"));
+        assert_eq!(Vec::<String>::new(), paths("name:c?.pdf"));
+        assert!(db
+            .query("tag:taxes AND", SortOrder::Name)
+            .unwrap_err()
+            .to_string()
+            .contains("at position 13"));
+    }
+
+    #[test]
+    fn full_text_search() {
+        let db = create_search_db();
+        db.store_content(
+            &"aaaaaaa".to_string(),
+            &"Dear Olivia, please find attached the invoice for March.".to_string(),
+        )
+        .expect("Failed to store content");
+        db.store_content(
+            &"bbbbbbb".to_string(),
+            &"Your tax return has been received.".to_string(),
+        )
+        .expect("Failed to store content");
+
+        let hits = db.full_text_search("invoice").expect("Search failed");
+        assert_eq!(1, hits.len());
+        assert_eq!("aaaaaaa", hits[0].file.path);
+        assert_eq!(vec!["taxes".to_string()], hits[0].file.tags);
+        assert!(hits[0].snippet.contains("<mark>invoice</mark>"));
+
+        assert!(db.full_text_search("letter").unwrap().is_empty());
+        assert!(db.full_text_search("\"invoice").is_err());
+
+        if let Some(ref con) = db.connection {
+            con.execute("DELETE FROM files WHERE path = 'aaaaaaa'", [])
+                .expect("Failed to delete file");
+        }
+        assert!(db.full_text_search("invoice").unwrap().is_empty());
+    }
+
+    #[test]
+    fn delete_file() {
+        let db = create_search_db();
+        let vault = TempDir::new("delete");
+        fs::create_dir_all(vault.join("aaaaaaa")).expect("Failed to create folder");
+        fs::write(vault.join("aaaaaaa").join("aaaaaaa.pdf"), "content")
+            .expect("Failed to write file");
+
+        db.delete_file(&vault, &"aaaaaaa".to_string())
+            .expect("Failed to delete file");
+        assert!(!vault.join("aaaaaaa").exists());
+        assert!(!vault.join(".aaaaaaa.deleted").exists());
+        assert_eq!(
+            vec!["bbbbbbb", "ccccccc"],
+            search_paths(&db, &SearchQuery::default())
+        );
+        if let Some(ref con) = db.connection {
+            let count: u32 = con
+                .query_row("SELECT COUNT(*) FROM fileTags", [], |r| r.get(0))
+                .unwrap();
+            assert_eq!(2, count);
+        }
+
+        // A missing folder still removes the database entry
+        db.delete_file(&vault, &"bbbbbbb".to_string())
+            .expect("Failed to delete file");
+        assert_eq!(vec!["ccccccc"], search_paths(&db, &SearchQuery::default()));
+
+        assert!(db.delete_file(&vault, &"aaaaaaa".to_string()).is_err());
+    }
+
+    #[test]
+    fn update_metadata() {
+        let db = create_search_db();
+        db.update_file_metadata(
+            &"bbbbbbb".to_string(),
+            &["taxes".to_string(), "taxes".to_string()],
+            &HashMap::from([("person".to_string(), vec!["Olivia".to_string()])]),
+        )
+        .expect("Failed to update metadata");
+
+        let query = SearchQuery {
+            categories: HashMap::from([("person".to_string(), vec!["Olivia".to_string()])]),
+            ..Default::default()
+        };
+        let result = db.search(&query).expect("Search failed");
+        assert_eq!(2, result.len());
+        assert_eq!(vec!["taxes".to_string()], result[1].tags);
+
+        // An unknown tag rolls back the whole update
+        assert!(db
+            .update_file_metadata(
+                &"bbbbbbb".to_string(),
+                &["unknown".to_string()],
+                &HashMap::new(),
+            )
+            .is_err());
+        let result = db.search(&query).expect("Search failed");
+        assert_eq!(vec!["taxes".to_string()], result[1].tags);
+
+        // Tags given twice are added once
+        db.update_file_metadata(
+            &"bbbbbbb".to_string(),
+            &[
+                "draft".to_string(),
+                "taxes".to_string(),
+                "draft".to_string(),
+            ],
+            &HashMap::new(),
+        )
+        .expect("Failed to update metadata");
+        assert_eq!(vec!["aaaaaaa"], search_paths(&db, &query));
+        let query = SearchQuery {
+            tags: vec!["draft".to_string()],
+            ..Default::default()
+        };
+        let result = db.search(&query).expect("Search failed");
+        assert_eq!(
+            vec!["draft".to_string(), "taxes".to_string()],
+            result[0].tags
+        );
+    }
+
+    #[test]
+    fn multiple_values() {
+        let db = create_search_db();
+        let both = HashMap::from([(
+            "person".to_string(),
+            vec!["Olivia".to_string(), "Liam".to_string()],
+        )]);
+        assert!(matches!(
+            db.update_file_metadata(&"bbbbbbb".to_string(), &[], &both),
+            Err(Error::Invalid(_))
+        ));
+
+        let mut config = create_config(&["taxes", "draft"], &[("person", &["Olivia", "Liam"])]);
+        config.categories[0].multiple = true;
+        let plan = db.plan_config_consistency(&config).expect("Failed to plan");
+        assert_eq!(config.categories, plan.changed_categories);
+        db.apply_consistency_plan(&plan)
+            .expect("Failed to apply plan");
+        db.update_file_metadata(&"bbbbbbb".to_string(), &[], &both)
+            .expect("Failed to update metadata");
+
+        let mut query = SearchQuery {
+            categories: both.clone(),
+            ..Default::default()
+        };
+        let result = db.search(&query).expect("Search failed");
+        assert_eq!(2, result.len());
+        assert_eq!(
+            Some(&vec!["Liam".to_string(), "Olivia".to_string()]),
+            result[1].categories.get("person")
+        );
+        query.value_match = ValueMatch::All;
+        assert_eq!(vec!["bbbbbbb"], search_paths(&db, &query));
+
+        // Back to a single value would drop one of the values of the file
+        config.categories[0].multiple = false;
+        let plan = db.plan_config_consistency(&config).expect("Failed to plan");
+        assert!(matches!(
+            db.apply_consistency_plan(&plan),
+            Err(Error::Conflict(_))
+        ));
+    }
+
+    #[test]
+    fn search_values_json() {
+        // Searches used to take a single value per category
+        let query: SearchQuery = serde_json::from_value(serde_json::json!({
+            "categories": { "person": "Liam", "sender": ["ACME", "Tax office"] },
+            "value_match": "all",
+        }))
+        .expect("Invalid query");
+        assert_eq!(vec!["Liam".to_string()], query.categories["person"]);
+        assert_eq!(2, query.categories["sender"].len());
+        assert_eq!(ValueMatch::All, query.value_match);
+    }
+
+    #[test]
+    fn search_dates() {
+        let db = create_search_db();
+        for (path, date) in [("ddddddd", "2023-12-31"), ("eeeeeee", "2024-03-01")] {
+            db.store_file(
+                &path.to_string(),
+                &format!("{path}.pdf"),
+                Some(&date.to_string()),
+            )
+            .expect("Failed to store file");
+        }
+        assert!(db
+            .store_file(
+                &"fffffff".to_string(),
+                &"f.pdf".to_string(),
+                Some(&"2024-13-01".to_string())
+            )
+            .is_err());
+
+        let query = SearchQuery {
+            date_from: Some("2024-01-01".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(vec!["eeeeeee"], search_paths(&db, &query));
+
+        let query = SearchQuery {
+            date_from: Some("2023-01-01".to_string()),
+            date_to: Some("2024-03-01".to_string()),
+            sort: SortOrder::DateDescending,
+            ..Default::default()
+        };
+        assert_eq!(vec!["eeeeeee", "ddddddd"], search_paths(&db, &query));
+
+        let query = SearchQuery {
+            sort: SortOrder::Date,
+            ..Default::default()
+        };
+        assert_eq!(
+            vec!["ddddddd", "eeeeeee", "aaaaaaa", "bbbbbbb", "ccccccc"],
+            search_paths(&db, &query)
+        );
+
+        let paths: Vec<String> = db
+            .query("date:..2023-12-31 OR tag:draft", SortOrder::Name)
+            .expect("Query failed")
+            .into_iter()
+            .map(|f| f.path)
+            .collect();
+        assert_eq!(vec!["bbbbbbb", "ddddddd"], paths);
+    }
+
+    fn create_config(tags: &[&str], categories: &[(&str, &[&str])]) -> Config {
+        serde_json::from_value(serde_json::json!({
+            "folder": "/tmp",
+            "save_date": false,
+            "categories": categories
+                .iter()
+                .map(|(name, values)| serde_json::json!({ "name": name, "values": values }))
+                .collect::<Vec<serde_json::Value>>(),
+            "tags": tags,
+        }))
+        .expect("Invalid config")
+    }
+
+    #[test]
+    fn consistency_plan() {
+        let db = create_search_db();
+        let config = create_config(
+            &["taxes", "letters"],
+            &[("person", &["Olivia", "Emma"]), ("format", &["mail"])],
+        );
+        let plan = db.plan_config_consistency(&config).expect("Failed to plan");
+        assert_eq!(vec!["letters".to_string()], plan.added_tags);
+        assert_eq!(
+            vec![Removal {
+                name: "draft".to_string(),
+                files: 1
+            }],
+            plan.removed_tags
+        );
+        assert_eq!(1, plan.added_categories.len());
+        assert_eq!(Vec::<Removal>::new(), plan.removed_categories);
+        assert_eq!(
+            vec![ValueChange {
+                category: "person".to_string(),
+                value: "Liam".to_string(),
+                files: 1
+            }],
+            plan.removed_values
+        );
+        assert_eq!("Emma", plan.added_values[0].value);
+        assert!(plan.is_destructive());
+        assert!(!plan.safe().is_destructive());
+        assert!(plan.check_confirmed(Some(&plan)).is_ok());
+        assert!(plan.check_confirmed(None).is_err());
+        let mut shown = plan.clone();
+        shown.removed_values[0].files = 0;
+        assert!(matches!(
+            plan.check_confirmed(Some(&shown)),
+            Err(Error::Conflict(_))
+        ));
+        assert!(plan.safe().check_confirmed(None).is_ok());
+
+        // Used tags and values survive the automatic check
+        db.check_config_consistency(&config)
+            .expect("Consistency check failed");
+        let mut tags = db.get_tags().unwrap();
+        tags.sort();
+        assert_eq!(vec!["draft", "letters", "taxes"], tags);
+        let plan = db.plan_config_consistency(&config).expect("Failed to plan");
+        assert!(plan.added_tags.is_empty() && plan.added_values.is_empty());
+
+        db.apply_consistency_plan(&plan)
+            .expect("Failed to apply plan");
+        let plan = db.plan_config_consistency(&config).expect("Failed to plan");
+        assert_eq!(ConsistencyPlan::default(), plan);
+        assert_eq!(
+            Vec::<String>::new(),
+            search_paths(
+                &db,
+                &SearchQuery {
+                    tags: vec!["draft".to_string()],
+                    ..Default::default()
+                }
+            )
+        );
+    }
+
+    #[test]
+    fn taxonomy() {
+        let db = create_search_db();
+        // Only what is missing is added, the vault keeps everything else
+        db.import_taxonomy(&create_config(
+            &["letters", "taxes"],
+            &[("person", &["Emma"]), ("format", &["mail"])],
+        ))
+        .expect("Failed to import taxonomy");
+
+        let mut config = create_config(&[], &[]);
+        db.load_taxonomy(&mut config)
+            .expect("Failed to load taxonomy");
+        assert_eq!(vec!["draft", "letters", "taxes"], config.tags);
+        assert_eq!(
+            vec![
+                Category {
+                    name: "person".to_string(),
+                    values: vec!["Olivia".to_string(), "Liam".to_string(), "Emma".to_string()],
+                    multiple: false,
+                },
+                Category {
+                    name: "format".to_string(),
+                    values: vec!["mail".to_string()],
+                    multiple: false,
+                }
+            ],
+            config.categories
+        );
+        assert_eq!(
+            ConsistencyPlan::default(),
+            db.plan_config_consistency(&config).unwrap()
+        );
+    }
+
+    #[test]
+    fn wait_for_other_process() {
+        let dir = TempDir::new("db-busy");
+        let mut db = Database::new();
+        db.open(dir.join("files.sqlite")).expect("Opening failed");
+        let mut other = Database::new();
+        other
+            .open(dir.join("files.sqlite"))
+            .expect("Opening failed");
+        other
+            .connection()
+            .unwrap()
+            .execute_batch("BEGIN EXCLUSIVE")
+            .unwrap();
+        let writer = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(200));
+            other.connection().unwrap().execute_batch("COMMIT").unwrap();
+        });
+        db.connection()
+            .unwrap()
+            .execute_batch("CREATE TABLE busy (id INTEGER)")
+            .expect("Waiting for the lock failed");
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn add_date_columns() {
+        let con = Connection::open_in_memory().expect("Database in memory failed");
+        con.execute_batch(
+            "CREATE TABLE files (id INTEGER PRIMARY KEY, path TEXT NOT NULL UNIQUE, name TEXT NOT NULL);
+             INSERT INTO files(path, name) VALUES ('aaaaaaa', 'a.pdf');",
+        )
+        .expect("Failed to create old table");
+        let db = Database {
+            connection: Some(con),
+        };
+        db.migrate(None).expect("Migration failed");
+        let files = db.search(&SearchQuery::default()).expect("Search failed");
+        assert_eq!(None, files[0].date);
+    }
+
+    #[test]
+    fn rename_keeps_associations() {
+        let db = create_search_db();
+        db.rename_tag(&"taxes".to_string(), &"tax".to_string())
+            .expect("Failed to rename tag");
+        db.rename_category(&"person".to_string(), &"people".to_string())
+            .expect("Failed to rename category");
+        db.rename_category_value(
+            &"people".to_string(),
+            &"Liam".to_string(),
+            &"William".to_string(),
+        )
+        .expect("Failed to rename value");
+
+        let query = SearchQuery {
+            tags: vec!["tax".to_string()],
+            categories: HashMap::from([("people".to_string(), vec!["William".to_string()])]),
+            ..Default::default()
+        };
+        let result = db.search(&query).expect("Search failed");
+        assert_eq!(1, result.len());
+        assert_eq!("bbbbbbb", result[0].path);
+        assert_eq!(vec!["draft".to_string(), "tax".to_string()], result[0].tags);
+
+        assert!(db
+            .rename_tag(&"unknown".to_string(), &"other".to_string())
+            .is_err());
+        assert!(db
+            .rename_tag(&"tax".to_string(), &"draft".to_string())
+            .is_err());
+        assert!(db
+            .rename_category_value(
+                &"people".to_string(),
+                &"William".to_string(),
+                &"Olivia".to_string(),
+            )
+            .is_err());
+        assert_eq!(1, db.search(&query).expect("Search failed").len());
+    }
+
+    #[test]
+    fn rename_needs_name() {
+        let db = create_search_db();
+        let person = "person".to_string();
+        for name in ["", " "] {
+            let name = name.to_string();
+            assert!(matches!(
+                db.rename_tag(&"taxes".to_string(), &name),
+                Err(Error::Invalid(_))
+            ));
+            assert!(matches!(
+                db.rename_category(&person, &name),
+                Err(Error::Invalid(_))
+            ));
+            assert!(matches!(
+                db.rename_category_value(&person, &"Liam".to_string(), &name),
+                Err(Error::Invalid(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn tag_hierarchy() {
+        let db = create_search_db();
+        db.insert_tag(&"finance/tax/2024".to_string())
+            .expect("Failed to insert tag");
+        db.insert_tag(&"finance/insurance".to_string())
+            .expect("Failed to insert tag");
+        assert!(matches!(
+            db.insert_tag(&"finance//2024".to_string()),
+            Err(Error::Invalid(_))
+        ));
+        create_file(&db, "ddddddd", &["finance/tax/2024"], &[]);
+        create_file(&db, "eeeeeee", &["finance/insurance"], &[]);
+
+        let tagged = |tag: &str| {
+            search_paths(
+                &db,
+                &SearchQuery {
+                    tags: vec![tag.to_string()],
+                    ..Default::default()
+                },
+            )
+        };
+        assert_eq!(vec!["ddddddd", "eeeeeee"], tagged("finance"));
+        assert_eq!(vec!["ddddddd"], tagged("finance/tax"));
+        let paths: Vec<String> = db
+            .query("tag:finance AND NOT tag:finance/tax", SortOrder::Name)
+            .expect("Query failed")
+            .into_iter()
+            .map(|f| f.path)
+            .collect();
+        assert_eq!(vec!["eeeeeee"], paths);
+
+        // Descendants move along with a renamed tag
+        db.rename_tag(&"finance".to_string(), &"money".to_string())
+            .expect("Failed to rename tag");
+        assert_eq!(vec!["ddddddd", "eeeeeee"], tagged("money"));
+        assert_eq!(
+            vec!["money/tax/2024".to_string()],
+            db.get_file(&"ddddddd".to_string()).unwrap().tags
+        );
+        db.rename_tag(&"money/tax".to_string(), &"archive/tax".to_string())
+            .expect("Failed to move tag");
+        assert_eq!(vec!["ddddddd"], tagged("archive"));
+        assert_eq!(vec!["eeeeeee"], tagged("money"));
+        assert!(matches!(
+            db.rename_tag(&"archive".to_string(), &"archive/old".to_string()),
+            Err(Error::Invalid(_))
+        ));
+
+        // Listing a tag keeps its ancestors, removing a parent counts the
+        // files of its descendants
+        let config = create_config(
+            &["taxes", "draft", "archive/tax/2024"],
+            &[("person", &["Olivia", "Liam"])],
+        );
+        let plan = db.plan_config_consistency(&config).expect("Failed to plan");
+        assert!(plan.added_tags.is_empty());
+        assert_eq!(
+            vec![
+                Removal {
+                    name: "money".to_string(),
+                    files: 1
+                },
+                Removal {
+                    name: "money/insurance".to_string(),
+                    files: 1
+                }
+            ],
+            plan.removed_tags
+        );
+        db.apply_consistency_plan(&plan)
+            .expect("Failed to apply plan");
+        let mut tags = db.get_tags().unwrap();
+        tags::sort(&mut tags);
+        assert_eq!(
+            vec![
+                "archive",
+                "archive/tax",
+                "archive/tax/2024",
+                "draft",
+                "taxes"
+            ],
+            tags
+        );
+    }
+
+    #[test]
+    fn tag_aliases() {
+        let db = create_search_db();
+        let taxes = "taxes".to_string();
+        db.add_alias(&"tax".to_string(), &taxes)
+            .expect("Failed to add alias");
+        assert!(matches!(
+            db.add_alias(&"draft".to_string(), &taxes),
+            Err(Error::Conflict(_))
+        ));
+        assert_eq!(
+            Err(Error::UnknownTag("tax".to_string())),
+            db.add_alias(&"steuer".to_string(), &"tax".to_string())
+        );
+        assert_eq!(taxes, db.resolve_tag(&"tax".to_string()).unwrap());
+        assert_eq!(vec!["taxes", "draft"], db.get_tags().unwrap());
+
+        // Tagging with an alias tags the file once
+        create_file(&db, "ddddddd", &["tax", "taxes"], &[]);
+        assert_eq!(
+            vec![taxes.clone()],
+            db.get_file(&"ddddddd".to_string()).unwrap().tags
+        );
+        db.update_file_metadata(
+            &"ccccccc".to_string(),
+            &["tax".to_string()],
+            &HashMap::new(),
+        )
+        .expect("Failed to update metadata");
+        let paths: Vec<String> = db
+            .query("tag:tax", SortOrder::Name)
+            .expect("Query failed")
+            .into_iter()
+            .map(|f| f.path)
+            .collect();
+        assert_eq!(vec!["aaaaaaa", "bbbbbbb", "ccccccc", "ddddddd"], paths);
+
+        // Files of a merged tag keep it under the new name
+        db.merge_tags(&"draft".to_string(), &"tax".to_string())
+            .expect("Failed to merge tags");
+        assert_eq!(vec!["taxes"], db.get_tags().unwrap());
+        assert_eq!(
+            vec![taxes.clone()],
+            db.get_file(&"bbbbbbb".to_string()).unwrap().tags
+        );
+        assert_eq!(
+            HashMap::from([
+                ("tax".to_string(), taxes.clone()),
+                ("draft".to_string(), taxes.clone())
+            ]),
+            db.get_aliases().unwrap()
+        );
+        assert!(matches!(
+            db.merge_tags(&taxes, &"draft".to_string()),
+            Err(Error::Invalid(_))
+        ));
+        db.remove_alias(&"tax".to_string())
+            .expect("Failed to remove alias");
+        assert!(db.resolve_tag(&"tax".to_string()).is_err());
+    }
+
+    #[test]
+    fn merge_in_config() {
+        let db = create_search_db();
+        let mut config = create_config(&["taxes"], &[("person", &["Olivia", "Liam"])]);
+        config.aliases = HashMap::from([
+            ("draft".to_string(), "taxes".to_string()),
+            ("tax".to_string(), "taxes".to_string()),
+        ]);
+        let plan = db.plan_config_consistency(&config).expect("Failed to plan");
+        assert!(plan.removed_tags.is_empty());
+        assert!(!plan.is_destructive());
+        assert_eq!(
+            vec![Alias {
+                alias: "draft".to_string(),
+                tag: "taxes".to_string()
+            }],
+            plan.merged_tags
+        );
+        assert_eq!("tax", plan.added_aliases[0].alias);
+        db.apply_consistency_plan(&plan)
+            .expect("Failed to apply plan");
+
+        let mut loaded = create_config(&[], &[]);
+        db.load_taxonomy(&mut loaded).unwrap();
+        assert_eq!(config.tags, loaded.tags);
+        assert_eq!(config.aliases, loaded.aliases);
+        assert_eq!(
+            ConsistencyPlan::default(),
+            db.plan_config_consistency(&config).unwrap()
+        );
+
+        config.aliases.remove("tax");
+        let plan = db.plan_config_consistency(&config).expect("Failed to plan");
+        assert_eq!("tax", plan.removed_aliases[0].alias);
+    }
+
+    #[test]
+    fn consistency_plan_rollback() {
+        let db = create_search_db();
+        let plan = ConsistencyPlan {
+            added_tags: vec!["letters".to_string(), "taxes".to_string()],
+            ..Default::default()
+        };
+        assert!(db.apply_consistency_plan(&plan).is_err());
+        assert!(!db.get_tags().unwrap().contains(&"letters".to_string()));
+    }
+
+    #[test]
+    fn structured_errors() {
+        assert_eq!(Err(Error::DatabaseClosed), Database::new().get_tags());
+
+        let db = create_search_db();
+        let taxes = "taxes".to_string();
+        assert_eq!(
+            Err(Error::UnknownTag("letters".to_string())),
+            db.rename_tag(&"letters".to_string(), &taxes)
+        );
+        assert_eq!(
+            Err(Error::Conflict(
+                "Failed to rename tag: Tag taxes already exists".to_string()
+            )),
+            db.rename_tag(&"draft".to_string(), &taxes)
+        );
+        assert_eq!(
+            Err(Error::UnknownFile("ddddddd".to_string())),
+            db.associate_tag_with_file(&"ddddddd".to_string(), &taxes)
+        );
+        assert_eq!(
+            Err(Error::UnknownCategoryValue(
+                "person".to_string(),
+                "Emma".to_string()
+            )),
+            db.associate_value_with_file(
+                &"ccccccc".to_string(),
+                &"person".to_string(),
+                &"Emma".to_string()
+            )
+        );
+        assert!(matches!(
+            db.search(&SearchQuery {
+                date_from: Some("2024-13-01".to_string()),
+                ..Default::default()
+            }),
+            Err(Error::Invalid(_))
+        ));
+    }
 }
@@ -0,0 +1,131 @@
+use serde::ser::{Serialize, SerializeStruct, Serializer};
+use std::fmt;
+
+/// Errors of the archive. They are sent to the frontend as `{ code, message }`
+/// so it can react to the kind of error and still show a readable message.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    /// No vault is configured yet
+    NotConfigured,
+    /// The database of the vault is not opened
+    DatabaseClosed,
+    UnknownTag(String),
+    UnknownCategory(String),
+    /// A value that is not declared for the category
+    UnknownCategoryValue(String, String),
+    /// No file is stored at the path
+    UnknownFile(String),
+    /// No vault of that name is in the config
+    UnknownVault(String),
+    /// The content is already stored in the vault at the location
+    Duplicate(String),
+    /// The request clashes with what is stored, e.g. a name that is taken
+    Conflict(String),
+    /// Malformed input, like a date, pattern, query or template
+    Invalid(String),
+    Io(String),
+    Sqlite(String),
+    /// Something that should not happen, like a poisoned lock
+    Internal(String),
+}
+
+impl Error {
+    /// The machine-readable kind of the error.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::NotConfigured => "not_configured",
+            Error::DatabaseClosed => "database_closed",
+            Error::UnknownTag(_) => "unknown_tag",
+            Error::UnknownCategory(_) => "unknown_category",
+            Error::UnknownCategoryValue(_, _) => "unknown_category_value",
+            Error::UnknownFile(_) => "unknown_file",
+            Error::UnknownVault(_) => "unknown_vault",
+            Error::Duplicate(_) => "duplicate",
+            Error::Conflict(_) => "conflict",
+            Error::Invalid(_) => "invalid",
+            Error::Io(_) => "io",
+            Error::Sqlite(_) => "sqlite",
+            Error::Internal(_) => "internal",
+        }
+    }
+
+    /// Prefixes free-form messages with what failed. Errors about a known
+    /// tag, category, value, file or vault already tell.
+    pub fn context(self, context: &str) -> Error {
+        match self {
+            Error::Conflict(msg) => Error::Conflict(format!("{context}: {msg}")),
+            Error::Invalid(msg) => Error::Invalid(format!("{context}: {msg}")),
+            Error::Io(msg) => Error::Io(format!("{context}: {msg}")),
+            Error::Sqlite(msg) => Error::Sqlite(format!("{context}: {msg}")),
+            Error::Internal(msg) => Error::Internal(format!("{context}: {msg}")),
+            err => err,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::NotConfigured => write!(f, "No vault is configured"),
+            Error::DatabaseClosed => write!(f, "The database is not opened"),
+            Error::UnknownTag(tag) => write!(f, "Unknown tag {tag}"),
+            Error::UnknownCategory(category) => write!(f, "Unknown category {category}"),
+            Error::UnknownCategoryValue(category, value) => {
+                write!(f, "Unknown value {value} of category {category}")
+            }
+            Error::UnknownFile(path) => write!(f, "No file stored at {path}"),
+            Error::UnknownVault(name) => write!(f, "Unknown vault {name}"),
+            Error::Duplicate(location) => write!(f, "Already imported as {location}"),
+            Error::Conflict(msg)
+            | Error::Invalid(msg)
+            | Error::Io(msg)
+            | Error::Sqlite(msg)
+            | Error::Internal(msg) => write!(f, "{msg}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<rusqlite::Error> for Error {
+    fn from(err: rusqlite::Error) -> Self {
+        Error::Sqlite(err.to_string())
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error::Io(err.to_string())
+    }
+}
+
+impl Serialize for Error {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("Error", 2)?;
+        state.serialize_field("code", self.code())?;
+        state.serialize_field("message", &self.to_string())?;
+        state.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serialize_error() {
+        assert_eq!(
+            serde_json::json!({"code": "unknown_tag", "message": "Unknown tag urgent"}),
+            serde_json::to_value(Error::UnknownTag("urgent".to_string())).unwrap()
+        );
+        let err: Error = std::io::Error::other("disk full").into();
+        assert_eq!(
+            Error::Io("Importing file failed: disk full".to_string()),
+            err.context("Importing file failed")
+        );
+        assert_eq!(
+            Error::UnknownTag("urgent".to_string()),
+            Error::UnknownTag("urgent".to_string()).context("Importing file failed")
+        );
+    }
+}
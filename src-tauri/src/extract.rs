@@ -0,0 +1,99 @@
+use log::{debug, warn};
+use std::fs;
+use std::panic;
+use std::path::Path;
+
+/// Extracts the text of a document, so it can be indexed for full-text
+/// search. Returns `None` for unsupported file types or if the extraction
+/// fails, as a missing index should never prevent an import.
+pub fn extract_text(path: &Path) -> Option<String> {
+    let extension = path.extension()?.to_str()?.to_lowercase();
+    let text = match extension.as_str() {
+        "txt" | "md" | "markdown" => fs::read_to_string(path).map_err(|err| err.to_string()),
+        "html" | "htm" => fs::read_to_string(path)
+            .map(|html| strip_html(&html))
+            .map_err(|err| err.to_string()),
+        // pdf-extract panics on many malformed files
+        "pdf" => match panic::catch_unwind(|| pdf_extract::extract_text(path)) {
+            Ok(text) => text.map_err(|err| err.to_string()),
+            Err(_) => Err("the PDF reader panicked".to_string()),
+        },
+        _ => {
+            debug!("No text extraction for {:?}", path);
+            return None;
+        }
+    };
+
+    match text {
+        Ok(text) => Some(text),
+        Err(err) => {
+            warn!("Extracting text from {:?} failed: {err}", path);
+            None
+        }
+    }
+}
+
+fn strip_html(html: &str) -> String {
+    // ASCII lowercasing keeps the byte offsets, so both can be indexed alike
+    let lower = html.to_ascii_lowercase();
+    let mut text = String::new();
+    let mut offset = 0;
+
+    while let Some(start) = html[offset..].find('<').map(|start| offset + start) {
+        text.push_str(&html[offset..start]);
+        text.push(' ');
+        let tag = &lower[start..];
+        // Skip the content of script and style elements entirely
+        let closing = if tag.starts_with("<script") {
+            "</script>"
+        } else if tag.starts_with("<style") {
+            "</style>"
+        } else {
+            ">"
+        };
+        match tag.find(closing) {
+            Some(end) => offset = start + end + closing.len(),
+            None => offset = html.len(),
+        }
+    }
+    text.push_str(&html[offset..]);
+
+    decode_entities(&text)
+        .split_whitespace()
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
+fn decode_entities(text: &str) -> String {
+    text.replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn html_stripping() {
+        let html = "<html><head><style>p { color: red; }</style><script>let a = 1 < 2;</script></head>\n<body><p>Dear  Olivia,</p><p>Tom &amp; Jerry</p></body></html>";
+        assert_eq!("Dear Olivia, Tom & Jerry", strip_html(html));
+    }
+
+    #[test]
+    fn broken_pdf() {
+        let dir = TempDir::new("extract-broken");
+        let path = dir.join("broken.pdf");
+        fs::write(&path, "%PDF-1.7\n1 0 obj << /Type /Catalog >>").unwrap();
+        assert_eq!(None, extract_text(&path));
+    }
+
+    #[test]
+    fn unsupported_extension() {
+        assert_eq!(None, extract_text(Path::new("image.png")));
+    }
+}
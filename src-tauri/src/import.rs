@@ -0,0 +1,429 @@
+use crate::config::Config;
+use crate::db::{Database, FileEntry, FileMetadata};
+use crate::error::Error;
+use crate::extract;
+use crate::rules;
+use crate::transfer;
+use crate::vault;
+use log::info;
+use std::path::Path;
+
+/// The date to store for a new document. Without a date given, the document
+/// is dated to the day of the import. No date is stored if the config does not
+/// track dates, so giving one is refused.
+pub fn document_date(config: &Config, date: Option<String>) -> Result<Option<String>, Error> {
+    match (config.save_date(), date) {
+        (true, date) => {
+            Ok(Some(date.unwrap_or_else(|| {
+                chrono::Local::now().format("%Y-%m-%d").to_string()
+            })))
+        }
+        (false, None) => Ok(None),
+        (false, Some(date)) => Err(Error::Invalid(format!(
+            "Cannot store the date {date}, dates are not tracked"
+        ))),
+    }
+}
+
+/// The file in the vault with the same content as the file at `path`.
+pub fn find_duplicate(db: &Database, path: &Path) -> Result<Option<FileEntry>, Error> {
+    db.get_file_by_hash(&vault::hash_file(path)?)
+}
+
+/// Copies, moves or links a file into the vault, as set by the import mode of
+/// the config, at the location chosen by its layout, and stores it with the
+/// given metadata, extended by the matching rules. Files already in the vault
+/// are refused unless `allow_duplicate` is set. If any step fails, the
+/// database is rolled back and the new location is removed again, and the
+/// original is kept. Returns the path of the file in the database.
+pub fn import_file(
+    db: &Database,
+    config: &Config,
+    path: &Path,
+    metadata: &FileMetadata,
+    allow_duplicate: bool,
+) -> Result<String, Error> {
+    let filename = match path.file_name().and_then(|name| name.to_str()) {
+        Some(name) => name.to_string(),
+        None => return Err(Error::Invalid(format!("Invalid file name: {:?}", path))),
+    };
+
+    let hash = vault::hash_file(path).map_err(|err| err.context("Importing file failed"))?;
+    if let Some(existing) = db.get_file_by_hash(&hash)? {
+        let location = existing.relative_location();
+        if !allow_duplicate {
+            return Err(Error::Duplicate(location.display().to_string()));
+        }
+        info!("Importing duplicate of {}", location.display());
+    }
+
+    // Rules look at the original location of the file
+    let content = extract::extract_text(path);
+    let matched = rules::evaluate(&config.rules, path, content.as_deref())?;
+    if !matched.rules.is_empty() {
+        info!("Applying rules {:?}", matched.rules);
+    }
+    let metadata = matched.apply(metadata);
+
+    let target = config
+        .layout
+        .reserve(&config.folder(), &filename, &hash, &metadata)?;
+    info!("New file location: {:?}", target.file);
+
+    let result = transfer::transfer(config.import_mode, path, &target.file, &hash).and_then(|()| {
+        db.store_import(
+            &target.path,
+            &filename,
+            target.kind,
+            &metadata,
+            content.as_ref(),
+            Some(&hash),
+        )
+    });
+    match result {
+        Ok(()) => {
+            transfer::finish(config.import_mode, path);
+            Ok(target.path)
+        }
+        Err(error) => {
+            target.remove();
+            Err(error)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::SearchQuery;
+    use crate::layout::{Layout, LayoutKind};
+    use crate::test_util::TestVault;
+    use crate::transfer::ImportMode;
+    use std::collections::HashMap;
+    use std::fs;
+
+    fn settings() -> serde_json::Value {
+        serde_json::json!({
+            "save_date": false,
+            "categories": [{ "name": "person", "values": ["Olivia", "Liam"] }],
+            "tags": ["taxes"],
+        })
+    }
+
+    fn create_vault(name: &str) -> TestVault {
+        let vault = TestVault::new(name, settings());
+        fs::write(vault.root.join("letter.txt"), "Dear Olivia").expect("Failed to write file");
+        vault
+    }
+
+    fn import(
+        vault: &TestVault,
+        tags: &[&str],
+        categories: &[(&str, &str)],
+    ) -> Result<String, Error> {
+        import_file(
+            &vault.db,
+            &vault.config,
+            &vault.root.join("letter.txt"),
+            &FileMetadata {
+                tags: tags.iter().map(|t| t.to_string()).collect(),
+                categories: categories
+                    .iter()
+                    .map(|(c, v)| (c.to_string(), vec![v.to_string()]))
+                    .collect(),
+                date: None,
+            },
+            false,
+        )
+    }
+
+    /// Modifies the database behind the back of `Database` to inject
+    /// failures.
+    fn execute(vault: &TestVault, sql: &str) {
+        rusqlite::Connection::open(vault.vault.join("files.sqlite"))
+            .expect("Failed to open database")
+            .execute_batch(sql)
+            .expect("Failed to execute SQL");
+    }
+
+    /// Asserts that the vault only contains the database and no file is
+    /// stored in it.
+    fn assert_empty(vault: &TestVault) {
+        let entries: Vec<String> = fs::read_dir(&vault.vault)
+            .expect("Failed to read vault")
+            .map(|e| e.unwrap().file_name().to_str().unwrap().to_string())
+            .filter(|name| !name.starts_with("files.sqlite"))
+            .collect();
+        assert_eq!(Vec::<String>::new(), entries);
+        assert!(vault
+            .db
+            .search(&SearchQuery::default())
+            .expect("Search failed")
+            .is_empty());
+        assert!(vault
+            .db
+            .full_text_search("Olivia")
+            .expect("Search failed")
+            .is_empty());
+    }
+
+    #[test]
+    fn date_without_tracking() {
+        let mut settings = settings();
+        settings["folder"] = serde_json::json!("/tmp");
+        let config: Config = serde_json::from_value(settings).expect("Invalid config");
+        assert_eq!(None, document_date(&config, None).unwrap());
+        assert!(matches!(
+            document_date(&config, Some("2024-03-01".to_string())),
+            Err(Error::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn import_success() {
+        let vault = create_vault("import-success");
+        let folder = import(&vault, &["taxes"], &[("person", "Olivia")]).expect("Import failed");
+        assert!(vault.vault.join(&folder).join("letter.txt").exists());
+
+        let files = vault.db.search(&SearchQuery::default()).unwrap();
+        assert_eq!(1, files.len());
+        assert_eq!(folder, files[0].path);
+        assert_eq!(vec!["taxes".to_string()], files[0].tags);
+        assert_eq!(1, vault.db.full_text_search("Olivia").unwrap().len());
+    }
+
+    #[test]
+    fn import_applies_rules() {
+        let mut vault = create_vault("import-rules");
+        vault.config.rules = serde_json::from_value(serde_json::json!([
+            { "name": "letters", "contains": "dear", "categories": { "person": "Liam" } },
+            { "name": "taxes", "extension": "txt", "tags": ["taxes"] },
+            { "name": "pdf", "extension": "pdf", "tags": ["unknown"] },
+        ]))
+        .expect("Invalid rules");
+        let metadata = FileMetadata {
+            categories: HashMap::from([("person".to_string(), vec!["Olivia".to_string()])]),
+            ..Default::default()
+        };
+        import_file(
+            &vault.db,
+            &vault.config,
+            &vault.root.join("letter.txt"),
+            &metadata,
+            false,
+        )
+        .expect("Import failed");
+
+        let files = vault.db.search(&SearchQuery::default()).unwrap();
+        assert_eq!(vec!["taxes".to_string()], files[0].tags);
+        assert_eq!(
+            Some(&vec!["Olivia".to_string()]),
+            files[0].categories.get("person")
+        );
+    }
+
+    #[test]
+    fn import_duplicate() {
+        let vault = create_vault("import-duplicate");
+        let folder = import(&vault, &["taxes"], &[]).expect("Import failed");
+        let letter = vault.root.join("letter.txt");
+        assert_eq!(
+            folder,
+            find_duplicate(&vault.db, &letter).unwrap().unwrap().path
+        );
+
+        let result = import(&vault, &[], &[]);
+        assert_eq!(
+            Error::Duplicate(format!("{folder}/letter.txt")),
+            result.unwrap_err()
+        );
+        assert_eq!(1, vault.db.search(&SearchQuery::default()).unwrap().len());
+
+        import_file(
+            &vault.db,
+            &vault.config,
+            &letter,
+            &FileMetadata::default(),
+            true,
+        )
+        .expect("Import failed");
+        assert_eq!(1, vault.db.find_duplicates().unwrap().len());
+    }
+
+    #[test]
+    fn import_layouts() {
+        let mut vault = create_vault("import-layouts");
+        vault.config.layout = Layout::Template {
+            template: "letters/{stem}.{ext}".to_string(),
+        };
+        let path = import(&vault, &["taxes"], &[]).expect("Import failed");
+        assert_eq!("letters/letter.txt", path);
+        assert!(vault.vault.join("letters/letter.txt").is_file());
+
+        vault.config.layout = Layout::ContentAddressed;
+        fs::write(vault.root.join("letter.txt"), "Dear Liam").unwrap();
+        let folder = import(&vault, &[], &[]).expect("Import failed");
+        assert!(vault.vault.join(&folder).join("letter.txt").is_file());
+
+        assert_eq!(
+            LayoutKind::Template,
+            vault.db.get_file(&path).unwrap().layout
+        );
+        assert_eq!(
+            LayoutKind::ContentAddressed,
+            vault.db.get_file(&folder).unwrap().layout
+        );
+
+        for file in vault.db.search(&SearchQuery::default()).unwrap() {
+            vault
+                .db
+                .delete_file(&vault.vault, &file.path)
+                .expect("Deleting failed");
+        }
+        assert_empty(&vault);
+    }
+
+    #[test]
+    fn import_filing_template() {
+        let mut vault = create_vault("import-filing");
+        vault.config.layout = Layout::Template {
+            template: "{category:person|Unknown}/{tags}/{name}".to_string(),
+        };
+        let first = import(&vault, &["taxes"], &[("person", "Olivia")]).expect("Import failed");
+        assert_eq!("Olivia/taxes/letter.txt", first);
+        fs::write(vault.root.join("letter.txt"), "Dear Liam").unwrap();
+        let second = import(&vault, &["taxes"], &[("person", "Olivia")]).expect("Import failed");
+        assert_eq!("Olivia/taxes/letter (2).txt", second);
+        fs::write(vault.root.join("letter.txt"), "Dear Emma").unwrap();
+        let third = import(&vault, &[], &[]).expect("Import failed");
+        assert_eq!("Unknown/letter.txt", third);
+        assert_eq!(
+            "Dear Liam",
+            fs::read_to_string(vault.vault.join(&second)).unwrap()
+        );
+    }
+
+    #[test]
+    fn import_modes() {
+        let mut vault = create_vault("import-modes");
+        let original = vault.root.join("letter.txt");
+        vault.config.import_mode = ImportMode::Hardlink;
+        let linked = import(&vault, &[], &[]).expect("Import failed");
+        assert!(original.exists());
+
+        // A failed move keeps the original. Writing to the linked original
+        // would change the vault as well.
+        vault.config.import_mode = ImportMode::Move;
+        fs::remove_file(&original).unwrap();
+        fs::write(&original, "Dear Liam").unwrap();
+        assert!(import(&vault, &["unknown"], &[]).is_err());
+        assert!(original.exists());
+
+        let moved = import(&vault, &[], &[]).expect("Import failed");
+        assert!(!original.exists());
+        assert_eq!(
+            "Dear Liam",
+            fs::read_to_string(vault.vault.join(&moved).join("letter.txt")).unwrap()
+        );
+        assert_eq!(
+            "Dear Olivia",
+            fs::read_to_string(vault.vault.join(&linked).join("letter.txt")).unwrap()
+        );
+    }
+
+    #[test]
+    fn import_missing_vault() {
+        let vault = create_vault("import-missing-vault");
+        let mut settings = settings();
+        settings["folder"] = serde_json::json!(vault.root.join("missing"));
+        let missing: Config = serde_json::from_value(settings).expect("Invalid config");
+        let result = import_file(
+            &vault.db,
+            &missing,
+            &vault.root.join("letter.txt"),
+            &FileMetadata::default(),
+            false,
+        );
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .starts_with("Creating folder failed"));
+        assert_empty(&vault);
+    }
+
+    #[test]
+    fn import_missing_source() {
+        let vault = create_vault("import-missing-source");
+        let result = import_file(
+            &vault.db,
+            &vault.config,
+            &vault.root.join("missing.txt"),
+            &FileMetadata::default(),
+            false,
+        );
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .starts_with("Importing file failed"));
+        assert_empty(&vault);
+    }
+
+    #[test]
+    fn import_file_insert_failure() {
+        let vault = create_vault("import-file-failure");
+        execute(
+            &vault,
+            "CREATE TRIGGER failFiles BEFORE INSERT ON files BEGIN SELECT RAISE(ABORT, 'injected'); END;",
+        );
+        let result = import(&vault, &["taxes"], &[]);
+        assert!(result.unwrap_err().to_string().contains("injected"));
+        assert_empty(&vault);
+    }
+
+    #[test]
+    fn import_unknown_tag() {
+        let vault = create_vault("import-unknown-tag");
+        assert_eq!(
+            Err(Error::UnknownTag("unknown".to_string())),
+            import(&vault, &["taxes", "unknown"], &[])
+        );
+        assert_empty(&vault);
+    }
+
+    #[test]
+    fn import_unknown_value() {
+        let vault = create_vault("import-unknown-value");
+        assert_eq!(
+            Err(Error::UnknownCategory("person2".to_string())),
+            import(
+                &vault,
+                &["taxes"],
+                &[("person", "Olivia"), ("person2", "Liam")]
+            )
+        );
+        assert_eq!(
+            Err(Error::UnknownCategoryValue(
+                "person".to_string(),
+                "Emma".to_string()
+            )),
+            import(&vault, &["taxes"], &[("person", "Emma")])
+        );
+        assert_empty(&vault);
+    }
+
+    #[test]
+    fn import_content_failure() {
+        let vault = create_vault("import-content-failure");
+        execute(
+            &vault,
+            "DROP TABLE fileContents; CREATE TABLE fileContents (id INTEGER);",
+        );
+        let result = import(&vault, &["taxes"], &[("person", "Olivia")]);
+        assert!(result.unwrap_err().to_string().contains("file content"));
+        execute(
+            &vault,
+            "DROP TABLE fileContents; CREATE VIRTUAL TABLE fileContents USING fts5(content);",
+        );
+        assert_empty(&vault);
+    }
+}
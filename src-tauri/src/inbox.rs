@@ -0,0 +1,445 @@
+use crate::config::{Config, Inbox};
+use crate::db::{Database, FileMetadata};
+use crate::error::Error;
+use crate::import;
+use log::{debug, info, warn};
+use notify::event::{AccessKind, AccessMode, ModifyKind, RenameMode};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use serde::Serialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Folders inside the inbox. They are hidden, so they are never picked up as
+/// new files themselves.
+const PENDING: &str = ".pending";
+const IMPORTED: &str = ".imported";
+const FAILED: &str = ".failed";
+const DUPLICATES: &str = ".duplicates";
+
+/// What happened to a file dropped into the inbox.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum Outcome {
+    Imported {
+        name: String,
+        folder: String,
+    },
+    Pending {
+        name: String,
+    },
+    /// The content is already stored in the vault as `existing`
+    Duplicate {
+        name: String,
+        existing: String,
+    },
+    Failed {
+        name: String,
+        error: Error,
+    },
+}
+
+/// Watches the inbox as long as it is not dropped.
+pub struct InboxWatcher {
+    _watcher: RecommendedWatcher,
+}
+
+fn inbox(config: &Config) -> Result<&Inbox, Error> {
+    match config.inbox {
+        Some(ref inbox) => Ok(inbox),
+        None => Err(Error::Invalid("No inbox configured.".to_string())),
+    }
+}
+
+fn file_name(path: &Path) -> Result<String, Error> {
+    match path.file_name().and_then(|name| name.to_str()) {
+        Some(name) => Ok(name.to_string()),
+        None => Err(Error::Invalid(format!("Invalid file name: {:?}", path))),
+    }
+}
+
+/// Whether the path is a file that should be processed. Hidden files are
+/// skipped, as many programs use them while a file is still being written.
+pub fn is_candidate(path: &Path) -> bool {
+    let hidden = match path.file_name().and_then(|name| name.to_str()) {
+        Some(name) => name.starts_with('.'),
+        None => true,
+    };
+    !hidden && path.is_file()
+}
+
+/// Moves a file into `dir`. If the name is taken, a number is appended.
+fn move_into(dir: &Path, path: &Path) -> Result<PathBuf, Error> {
+    fs::create_dir_all(dir)
+        .map_err(|err| Error::from(err).context(&format!("Creating folder {:?} failed", dir)))?;
+    let name = file_name(path)?;
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, format!(".{extension}")),
+        _ => (name.as_str(), String::new()),
+    };
+    let mut target = dir.join(&name);
+    let mut counter = 1;
+    while target.exists() {
+        target = dir.join(format!("{stem} ({counter}){extension}"));
+        counter += 1;
+    }
+    fs::rename(path, &target)
+        .map_err(|err| Error::from(err).context(&format!("Moving {:?} failed", path)))?;
+    debug!("Moved {:?} to {:?}", path, target);
+    Ok(target)
+}
+
+/// Moves an imported file to the imported files of the inbox, unless the
+/// import mode already moved it into the vault.
+fn keep_original(inbox: &Path, path: &Path) -> Result<(), Error> {
+    if path.exists() {
+        move_into(&inbox.join(IMPORTED), path)?;
+    }
+    Ok(())
+}
+
+/// Moves a file of the inbox aside, as it is already imported as `existing`.
+fn skip_duplicate(
+    folder: &Path,
+    path: &Path,
+    name: String,
+    existing: String,
+) -> Result<Outcome, Error> {
+    info!("Skipping {name}, it is already imported as {existing}");
+    move_into(&folder.join(DUPLICATES), path)?;
+    Ok(Outcome::Duplicate { name, existing })
+}
+
+/// Processes a new file of the inbox. It is either imported with the default
+/// metadata of the inbox or moved to the pending files for review. Either way
+/// it leaves the inbox. Files that cannot be imported are moved aside too, so
+/// they are not retried over and over.
+pub fn process_file(db: &Database, config: &Config, path: &Path) -> Result<Outcome, Error> {
+    let inbox = inbox(config)?;
+    let folder = PathBuf::from(&inbox.folder);
+    let name = file_name(path)?;
+
+    if inbox.review {
+        if let Some(existing) = import::find_duplicate(db, path)? {
+            let existing = existing.relative_location().display().to_string();
+            return skip_duplicate(&folder, path, name, existing);
+        }
+        let target = move_into(&folder.join(PENDING), path)?;
+        info!("Queued {name} for review");
+        return Ok(Outcome::Pending {
+            name: file_name(&target)?,
+        });
+    }
+
+    let metadata = FileMetadata {
+        tags: inbox.tags.clone(),
+        categories: inbox.categories.clone(),
+        date: import::document_date(config, None)?,
+    };
+    match import::import_file(db, config, path, &metadata, false) {
+        Ok(vault_folder) => {
+            info!("Imported {name} from the inbox into {vault_folder}");
+            keep_original(&folder, path)?;
+            Ok(Outcome::Imported {
+                name,
+                folder: vault_folder,
+            })
+        }
+        Err(Error::Duplicate(existing)) => skip_duplicate(&folder, path, name, existing),
+        Err(error) => {
+            warn!("Importing {name} from the inbox failed: {error}");
+            move_into(&folder.join(FAILED), path)?;
+            Ok(Outcome::Failed { name, error })
+        }
+    }
+}
+
+/// The files currently waiting in the inbox, e.g. dropped while it was not
+/// watched.
+pub fn files(config: &Config) -> Result<Vec<PathBuf>, Error> {
+    let folder = PathBuf::from(&inbox(config)?.folder);
+    let mut files: Vec<PathBuf> = fs::read_dir(&folder)
+        .map_err(|err| Error::from(err).context(&format!("Reading inbox {:?} failed", folder)))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| is_candidate(path))
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+/// Names of the files waiting for review.
+pub fn list_pending(config: &Config) -> Result<Vec<String>, Error> {
+    let pending = PathBuf::from(&inbox(config)?.folder).join(PENDING);
+    if !pending.exists() {
+        return Ok(Vec::new());
+    }
+    let mut names: Vec<String> = fs::read_dir(&pending)
+        .map_err(|err| Error::from(err).context("Reading pending files failed"))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| is_candidate(path))
+        .filter_map(|path| file_name(&path).ok())
+        .collect();
+    names.sort();
+    Ok(names)
+}
+
+fn pending_path(config: &Config, name: &str) -> Result<PathBuf, Error> {
+    // Only plain names, the pending folder must not be left
+    if Path::new(name).file_name().and_then(|n| n.to_str()) != Some(name) {
+        return Err(Error::Invalid(format!("Invalid file name: {name}")));
+    }
+    let path = PathBuf::from(&inbox(config)?.folder)
+        .join(PENDING)
+        .join(name);
+    if !is_candidate(&path) {
+        return Err(Error::Invalid(format!("No pending file {name}")));
+    }
+    Ok(path)
+}
+
+/// Imports a reviewed file. Returns the folder of the file in the vault.
+pub fn import_pending(
+    db: &Database,
+    config: &Config,
+    name: &str,
+    metadata: &FileMetadata,
+    allow_duplicate: bool,
+) -> Result<String, Error> {
+    let path = pending_path(config, name)?;
+    let folder = import::import_file(db, config, &path, metadata, allow_duplicate)?;
+    keep_original(&PathBuf::from(&inbox(config)?.folder), &path)?;
+    Ok(folder)
+}
+
+/// Deletes a pending file without importing it.
+pub fn discard_pending(config: &Config, name: &str) -> Result<(), Error> {
+    let path = pending_path(config, name)?;
+    fs::remove_file(&path)
+        .map_err(|err| Error::from(err).context(&format!("Deleting {name} failed")))
+}
+
+/// Whether the event signals a file that is completely written. On Linux,
+/// files are only complete once they are closed after writing or moved in.
+fn is_complete(kind: &EventKind) -> bool {
+    match kind {
+        EventKind::Access(AccessKind::Close(AccessMode::Write)) => true,
+        EventKind::Modify(ModifyKind::Name(RenameMode::To)) => true,
+        #[cfg(not(target_os = "linux"))]
+        EventKind::Create(notify::event::CreateKind::File) => true,
+        _ => false,
+    }
+}
+
+/// Watches the inbox folder and calls `handler` for every new file.
+pub fn watch(
+    folder: &Path,
+    handler: impl Fn(&Path) + Send + 'static,
+) -> Result<InboxWatcher, Error> {
+    let mut watcher =
+        notify::recommended_watcher(move |event: notify::Result<Event>| match event {
+            Ok(event) if is_complete(&event.kind) => {
+                for path in event.paths.iter().filter(|path| is_candidate(path)) {
+                    handler(path);
+                }
+            }
+            Ok(_) => (),
+            Err(err) => warn!("Watching the inbox failed: {err}"),
+        })
+        .map_err(|err| Error::Io(format!("Creating inbox watcher failed: {err}")))?;
+    watcher
+        .watch(folder, RecursiveMode::NonRecursive)
+        .map_err(|err| Error::Io(format!("Watching {:?} failed: {err}", folder)))?;
+    info!("Watching inbox {:?}", folder);
+    Ok(InboxWatcher { _watcher: watcher })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::SearchQuery;
+    use crate::test_util::{TempDir, TestVault};
+    use crate::transfer::ImportMode;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    struct TestInbox {
+        _root: TempDir,
+        inbox: PathBuf,
+        config: Config,
+        db: Database,
+    }
+
+    impl TestInbox {
+        fn new(name: &str, review: bool) -> TestInbox {
+            let TestVault {
+                root,
+                mut config,
+                db,
+                ..
+            } = TestVault::new(
+                name,
+                serde_json::json!({
+                    "save_date": false,
+                    "categories": [{ "name": "person", "values": ["Olivia", "Liam"] }],
+                    "tags": ["taxes", "scan"],
+                }),
+            );
+            let inbox = root.join("inbox");
+            fs::create_dir_all(&inbox).expect("Failed to create inbox");
+            config.inbox = serde_json::from_value(serde_json::json!({
+                "folder": inbox,
+                "review": review,
+                "tags": ["scan"],
+            }))
+            .expect("Invalid inbox");
+
+            TestInbox {
+                _root: root,
+                inbox,
+                config,
+                db,
+            }
+        }
+
+        fn drop_file(&self, name: &str) -> PathBuf {
+            let path = self.inbox.join(name);
+            fs::write(&path, "Dear Olivia").expect("Failed to write file");
+            path
+        }
+
+        fn entries(&self, dir: &str) -> Vec<String> {
+            let mut names: Vec<String> = fs::read_dir(self.inbox.join(dir))
+                .expect("Failed to read folder")
+                .map(|e| e.unwrap().file_name().to_str().unwrap().to_string())
+                .collect();
+            names.sort();
+            names
+        }
+    }
+
+    #[test]
+    fn import_with_default_tags() {
+        let inbox = TestInbox::new("inbox-import", false);
+        let path = inbox.drop_file("letter.txt");
+        inbox.drop_file(".letter.txt.swp");
+        assert_eq!(vec![path.clone()], files(&inbox.config).unwrap());
+
+        let outcome = process_file(&inbox.db, &inbox.config, &path).expect("Processing failed");
+        let files = inbox.db.search(&SearchQuery::default()).unwrap();
+        assert_eq!(1, files.len());
+        assert_eq!(vec!["scan".to_string()], files[0].tags);
+        assert_eq!(
+            Outcome::Imported {
+                name: "letter.txt".to_string(),
+                folder: files[0].path.clone()
+            },
+            outcome
+        );
+        assert!(!path.exists());
+        assert_eq!(vec!["letter.txt"], inbox.entries(IMPORTED));
+
+        // The same name again does not overwrite the processed file
+        let path = inbox.inbox.join("letter.txt");
+        fs::write(&path, "Dear Liam").unwrap();
+        process_file(&inbox.db, &inbox.config, &path).expect("Processing failed");
+        assert_eq!(
+            vec!["letter (1).txt", "letter.txt"],
+            inbox.entries(IMPORTED)
+        );
+    }
+
+    #[test]
+    fn move_into_vault() {
+        let mut inbox = TestInbox::new("inbox-move", false);
+        inbox.config.import_mode = ImportMode::Move;
+        let path = inbox.drop_file("letter.txt");
+        let outcome = process_file(&inbox.db, &inbox.config, &path).expect("Processing failed");
+        assert!(matches!(outcome, Outcome::Imported { .. }));
+        assert!(!path.exists());
+        assert!(!inbox.inbox.join(IMPORTED).exists());
+    }
+
+    #[test]
+    fn skip_duplicates() {
+        let inbox = TestInbox::new("inbox-duplicates", false);
+        let path = inbox.drop_file("letter.txt");
+        process_file(&inbox.db, &inbox.config, &path).expect("Processing failed");
+        let folder = inbox.db.search(&SearchQuery::default()).unwrap()[0]
+            .path
+            .clone();
+
+        let path = inbox.drop_file("copy.txt");
+        let outcome = process_file(&inbox.db, &inbox.config, &path).expect("Processing failed");
+        assert_eq!(
+            Outcome::Duplicate {
+                name: "copy.txt".to_string(),
+                existing: format!("{folder}/letter.txt"),
+            },
+            outcome
+        );
+        assert_eq!(vec!["copy.txt"], inbox.entries(DUPLICATES));
+        assert_eq!(1, inbox.db.search(&SearchQuery::default()).unwrap().len());
+    }
+
+    #[test]
+    fn failed_import_leaves_inbox() {
+        let mut inbox = TestInbox::new("inbox-failed", false);
+        inbox.config.inbox.as_mut().unwrap().tags = vec!["unknown".to_string()];
+        let path = inbox.drop_file("letter.txt");
+
+        let outcome = process_file(&inbox.db, &inbox.config, &path).expect("Processing failed");
+        assert!(matches!(outcome, Outcome::Failed { .. }));
+        assert!(!path.exists());
+        assert_eq!(vec!["letter.txt"], inbox.entries(FAILED));
+        assert!(inbox.db.search(&SearchQuery::default()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn review_pending_files() {
+        let inbox = TestInbox::new("inbox-review", true);
+        let letter = inbox.drop_file("letter.txt");
+        let ad = inbox.drop_file("ad.txt");
+        for path in [&letter, &ad] {
+            process_file(&inbox.db, &inbox.config, path).expect("Processing failed");
+        }
+        assert!(inbox.db.search(&SearchQuery::default()).unwrap().is_empty());
+        assert!(files(&inbox.config).unwrap().is_empty());
+        assert_eq!(
+            vec!["ad.txt".to_string(), "letter.txt".to_string()],
+            list_pending(&inbox.config).unwrap()
+        );
+
+        let metadata = FileMetadata {
+            tags: vec!["taxes".to_string()],
+            ..Default::default()
+        };
+        import_pending(&inbox.db, &inbox.config, "letter.txt", &metadata, false)
+            .expect("Import failed");
+        discard_pending(&inbox.config, "ad.txt").expect("Discarding failed");
+        assert!(list_pending(&inbox.config).unwrap().is_empty());
+        assert_eq!(vec!["letter.txt"], inbox.entries(IMPORTED));
+
+        let files = inbox.db.search(&SearchQuery::default()).unwrap();
+        assert_eq!(1, files.len());
+        assert_eq!(vec!["taxes".to_string()], files[0].tags);
+
+        assert!(import_pending(&inbox.db, &inbox.config, "ad.txt", &metadata, false).is_err());
+        assert!(discard_pending(&inbox.config, "../letter.txt").is_err());
+    }
+
+    #[test]
+    fn watch_new_files() {
+        let inbox = TestInbox::new("inbox-watch", false);
+        let (sender, receiver) = mpsc::channel();
+        let sender = std::sync::Mutex::new(sender);
+        let _watcher = watch(&inbox.inbox, move |path| {
+            sender.lock().unwrap().send(path.to_path_buf()).unwrap();
+        })
+        .expect("Watching failed");
+
+        let path = inbox.drop_file("letter.txt");
+        let received = receiver
+            .recv_timeout(Duration::from_secs(5))
+            .expect("No event received");
+        assert_eq!(path, received);
+    }
+}
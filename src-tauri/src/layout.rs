@@ -0,0 +1,550 @@
+use crate::db::FileMetadata;
+use crate::error::Error;
+use log::{debug, warn};
+use rand::{distributions::Alphanumeric, Rng};
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::ErrorKind;
+use std::path::{Component, Path, PathBuf};
+
+/// How often a new location is tried if the chosen one is taken.
+const ATTEMPTS: usize = 100;
+
+/// How new files are stored in the vault.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Layout {
+    /// A folder with a random name for each file
+    #[default]
+    Random,
+    /// A folder named by the content hash, grouped by its first two characters
+    ContentAddressed,
+    /// A path expanded from a template like `{date}-{name}`
+    Template { template: String },
+}
+
+/// The layout of a stored file, as recorded in the database.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LayoutKind {
+    #[default]
+    Random,
+    ContentAddressed,
+    Template,
+}
+
+impl LayoutKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            LayoutKind::Random => "random",
+            LayoutKind::ContentAddressed => "content_addressed",
+            LayoutKind::Template => "template",
+        }
+    }
+
+    /// The location of a stored file relative to the vault. Files stored in
+    /// their own folder are addressed by the folder, otherwise the path in the
+    /// database already points to the file.
+    pub fn relative_location(&self, path: &str, name: &str) -> PathBuf {
+        match self {
+            LayoutKind::Random | LayoutKind::ContentAddressed => Path::new(path).join(name),
+            LayoutKind::Template => PathBuf::from(path),
+        }
+    }
+}
+
+impl ToSql for LayoutKind {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.as_str()))
+    }
+}
+
+impl FromSql for LayoutKind {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value.as_str()? {
+            "random" => Ok(LayoutKind::Random),
+            "content_addressed" => Ok(LayoutKind::ContentAddressed),
+            "template" => Ok(LayoutKind::Template),
+            other => Err(FromSqlError::Other(
+                format!("Unknown layout {other}").into(),
+            )),
+        }
+    }
+}
+
+/// A location reserved for a new file. The folder or an empty file is
+/// already created, so no other import can take it.
+#[derive(Debug)]
+pub struct Target {
+    /// Path of the file in the database
+    pub path: String,
+    /// Where the file has to be copied to
+    pub file: PathBuf,
+    pub kind: LayoutKind,
+    vault: PathBuf,
+}
+
+impl Target {
+    /// Removes the reserved location again, e.g. after a failed import.
+    pub fn remove(&self) {
+        let root = self.vault.join(&self.path);
+        let result = if root.is_dir() {
+            fs::remove_dir_all(&root)
+        } else {
+            fs::remove_file(&root)
+        };
+        if let Err(err) = result {
+            warn!("Removing {:?} failed: {err}", root);
+        }
+        remove_empty_parents(&self.vault, &root);
+    }
+}
+
+/// Removes the folders between `path` and the vault that became empty.
+pub fn remove_empty_parents(vault: &Path, path: &Path) {
+    let mut current = path.parent();
+    while let Some(dir) = current {
+        if dir == vault || !dir.starts_with(vault) {
+            break;
+        }
+        // Fails for folders that are not empty
+        if fs::remove_dir(dir).is_err() {
+            break;
+        }
+        debug!("Removed empty folder {:?}", dir);
+        current = dir.parent();
+    }
+}
+
+fn random_name() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(7)
+        .map(char::from)
+        .collect()
+}
+
+/// Creates the folder `vault/path`, trying the candidates until one is free.
+fn create_folder(
+    vault: &Path,
+    mut candidates: impl Iterator<Item = String>,
+) -> Result<String, Error> {
+    for _ in 0..ATTEMPTS {
+        let path = match candidates.next() {
+            Some(path) => path,
+            None => break,
+        };
+        let folder = vault.join(&path);
+        if let Some(parent) = folder.parent() {
+            fs::create_dir_all(parent)
+                .map_err(|err| Error::from(err).context("Creating folder failed"))?;
+        }
+        match fs::create_dir(&folder) {
+            Ok(()) => return Ok(path),
+            Err(err) if err.kind() == ErrorKind::AlreadyExists => {
+                debug!("Folder {path} is taken");
+            }
+            Err(err) => return Err(Error::from(err).context("Creating folder failed")),
+        }
+    }
+    Err(Error::Conflict(
+        "Creating folder failed: no free folder found".to_string(),
+    ))
+}
+
+/// Creates an empty file at `vault/path`, numbering the name until it is free.
+fn create_file(vault: &Path, path: &str) -> Result<String, Error> {
+    // Only a dot in the file name starts the extension
+    let name_start = path.rfind('/').map(|i| i + 1).unwrap_or(0);
+    let (stem, extension) = match path[name_start..].rfind('.') {
+        Some(dot) if dot > 0 => path.split_at(name_start + dot),
+        _ => (path, ""),
+    };
+    for counter in 1..=ATTEMPTS {
+        let candidate = match counter {
+            1 => path.to_string(),
+            _ => format!("{stem} ({counter}){extension}"),
+        };
+        let file = vault.join(&candidate);
+        if let Some(parent) = file.parent() {
+            fs::create_dir_all(parent)
+                .map_err(|err| Error::from(err).context("Creating folder failed"))?;
+        }
+        match OpenOptions::new().write(true).create_new(true).open(&file) {
+            Ok(_) => return Ok(candidate),
+            Err(err) if err.kind() == ErrorKind::AlreadyExists => {
+                debug!("File {candidate} is taken");
+            }
+            Err(err) => return Err(Error::from(err).context("Creating file failed")),
+        }
+    }
+    Err(Error::Conflict(format!(
+        "Creating file failed: {path} is taken"
+    )))
+}
+
+/// Characters that are not allowed in file names on common file systems.
+const ILLEGAL: &[char] = &['<', '>', ':', '"', '/', '\\', '|', '?', '*'];
+
+/// Names Windows reserves for devices, regardless of the extension.
+const RESERVED: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Replaces the characters of a value that are illegal in paths, so values
+/// never add folders.
+fn sanitize_value(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            c if ILLEGAL.contains(&c) || c.is_control() => '_',
+            c => c,
+        })
+        .collect()
+}
+
+/// Makes a single folder or file name valid. Returns an empty string for
+/// names that consist only of spaces.
+fn sanitize_component(component: &str) -> String {
+    // Trailing dots and spaces are dropped by Windows
+    let name = component.trim_start().trim_end_matches(['.', ' ']);
+    if name.is_empty() && !component.trim().is_empty() {
+        // Names consisting of dots only
+        return "_".to_string();
+    }
+    let stem = name.split('.').next().unwrap_or_default();
+    if RESERVED.iter().any(|r| r.eq_ignore_ascii_case(stem)) {
+        return format!("_{name}");
+    }
+    name.to_string()
+}
+
+/// Expands the placeholders of a template into a path relative to the vault:
+/// `{name}`, `{stem}`, `{ext}`, `{date}`, `{hash}`, `{tags}` and
+/// `{category:<name>}`. `{placeholder|fallback}` is used for missing values,
+/// and folders without a name are left out.
+fn expand(
+    template: &str,
+    filename: &str,
+    hash: &str,
+    metadata: &FileMetadata,
+) -> Result<String, Error> {
+    if template.starts_with('/')
+        || template
+            .split('/')
+            .any(|component| component == "." || component == "..")
+    {
+        return Err(Error::Invalid(format!(
+            "Template {template} has to be a relative path"
+        )));
+    }
+    let (stem, extension) = match filename.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, extension),
+        _ => (filename, ""),
+    };
+    let date = match metadata.date {
+        Some(ref date) => date.clone(),
+        None => chrono::Local::now().format("%Y-%m-%d").to_string(),
+    };
+
+    let mut path = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        path.push_str(&rest[..start]);
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => {
+                return Err(Error::Invalid(format!(
+                    "Unclosed placeholder in template {template}"
+                )))
+            }
+        };
+        let (placeholder, fallback) = match rest[start + 1..end].split_once('|') {
+            Some((placeholder, fallback)) => (placeholder, fallback),
+            None => (&rest[start + 1..end], ""),
+        };
+        let value = match placeholder.split_once(':') {
+            Some(("category", category)) => metadata
+                .categories
+                .get(category)
+                .map(|values| values.join(", "))
+                .unwrap_or_default(),
+            None => match placeholder {
+                "name" => filename.to_string(),
+                "stem" => stem.to_string(),
+                "ext" => extension.to_string(),
+                "date" => date.clone(),
+                "hash" => hash.to_string(),
+                "tags" => metadata.tags.join(", "),
+                other => {
+                    return Err(Error::Invalid(format!(
+                        "Unknown placeholder {{{other}}} in template"
+                    )))
+                }
+            },
+            Some(_) => {
+                return Err(Error::Invalid(format!(
+                    "Unknown placeholder {{{placeholder}}} in template"
+                )))
+            }
+        };
+        match value.trim() {
+            "" => path.push_str(&sanitize_value(fallback)),
+            _ => path.push_str(&sanitize_value(&value)),
+        }
+        rest = &rest[end + 1..];
+    }
+    path.push_str(rest);
+
+    let components: Vec<String> = path
+        .split('/')
+        .map(|component| sanitize_component(&sanitize_value(component)))
+        .collect();
+    match components.last() {
+        Some(name) if !name.is_empty() => (),
+        _ => {
+            return Err(Error::Invalid(format!(
+                "Template {template} expands to an empty file name"
+            )))
+        }
+    }
+    let path = components
+        .into_iter()
+        .filter(|component| !component.is_empty())
+        .collect::<Vec<_>>()
+        .join("/");
+    debug_assert!(Path::new(&path)
+        .components()
+        .all(|c| matches!(c, Component::Normal(_))));
+    Ok(path)
+}
+
+impl Layout {
+    pub fn kind(&self) -> LayoutKind {
+        match self {
+            Layout::Random => LayoutKind::Random,
+            Layout::ContentAddressed => LayoutKind::ContentAddressed,
+            Layout::Template { .. } => LayoutKind::Template,
+        }
+    }
+
+    /// Checks that a template expands to a valid path.
+    pub fn check(&self) -> Result<(), Error> {
+        match self {
+            Layout::Template { template } => {
+                expand(template, "file.txt", "", &FileMetadata::default()).map(|_| ())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Reserves the location of a new file in the vault.
+    pub fn reserve(
+        &self,
+        vault: &Path,
+        filename: &str,
+        hash: &str,
+        metadata: &FileMetadata,
+    ) -> Result<Target, Error> {
+        // Only folders inside the vault are created
+        if !vault.is_dir() {
+            return Err(Error::Io(format!(
+                "Creating folder failed: no vault at {:?}",
+                vault
+            )));
+        }
+        let path = match self {
+            Layout::Random => create_folder(vault, std::iter::repeat_with(random_name))?,
+            Layout::ContentAddressed => {
+                let prefix = hash.get(..2).unwrap_or(hash);
+                // Duplicates get a numbered folder
+                let candidates = (1..).map(|counter| match counter {
+                    1 => format!("{prefix}/{hash}"),
+                    _ => format!("{prefix}/{hash}-{counter}"),
+                });
+                create_folder(vault, candidates)?
+            }
+            Layout::Template { template } => {
+                create_file(vault, &expand(template, filename, hash, metadata)?)?
+            }
+        };
+        let kind = self.kind();
+        Ok(Target {
+            file: vault.join(kind.relative_location(&path, filename)),
+            path,
+            kind,
+            vault: vault.to_path_buf(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+    use std::collections::HashMap;
+
+    fn metadata(date: Option<&str>) -> FileMetadata {
+        FileMetadata {
+            date: date.map(|d| d.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn random_layout() {
+        let vault = TempDir::new("layout-random");
+        let target = Layout::Random
+            .reserve(&vault, "letter.pdf", "abcdef", &metadata(None))
+            .expect("Reserving failed");
+        assert_eq!(7, target.path.len());
+        assert!(vault.join(&target.path).is_dir());
+        assert_eq!(vault.join(&target.path).join("letter.pdf"), target.file);
+
+        // Taken folders are skipped
+        let names = ["aaaaaaa".to_string(), "bbbbbbb".to_string()];
+        fs::create_dir(vault.join("aaaaaaa")).unwrap();
+        assert_eq!(
+            Ok("bbbbbbb".to_string()),
+            create_folder(&vault, names.into_iter())
+        );
+
+        target.remove();
+        assert!(!vault.join(&target.path).exists());
+    }
+
+    #[test]
+    fn content_addressed_layout() {
+        let vault = TempDir::new("layout-content");
+        let layout = Layout::ContentAddressed;
+        let first = layout
+            .reserve(&vault, "letter.pdf", "abcdef", &metadata(None))
+            .expect("Reserving failed");
+        let second = layout
+            .reserve(&vault, "copy.pdf", "abcdef", &metadata(None))
+            .expect("Reserving failed");
+        assert_eq!("ab/abcdef", first.path);
+        assert_eq!("ab/abcdef-2", second.path);
+        assert_eq!(vault.join("ab/abcdef-2/copy.pdf"), second.file);
+
+        first.remove();
+        assert!(vault.join("ab").exists());
+        second.remove();
+        assert!(!vault.join("ab").exists());
+        assert!(vault.exists());
+    }
+
+    #[test]
+    fn template_layout() {
+        let vault = TempDir::new("layout-template");
+        let layout = Layout::Template {
+            template: "{date}/{stem}-{hash}.{ext}".to_string(),
+        };
+        let target = layout
+            .reserve(
+                &vault,
+                "letter.pdf",
+                "abcdef",
+                &metadata(Some("2024-03-01")),
+            )
+            .expect("Reserving failed");
+        assert_eq!("2024-03-01/letter-abcdef.pdf", target.path);
+        assert_eq!(vault.join("2024-03-01/letter-abcdef.pdf"), target.file);
+        assert!(target.file.is_file());
+
+        let again = layout
+            .reserve(
+                &vault,
+                "letter.pdf",
+                "abcdef",
+                &metadata(Some("2024-03-01")),
+            )
+            .expect("Reserving failed");
+        assert_eq!("2024-03-01/letter-abcdef (2).pdf", again.path);
+
+        again.remove();
+        target.remove();
+        assert!(!vault.join("2024-03-01").exists());
+    }
+
+    #[test]
+    fn template_expansion() {
+        let data = metadata(Some("2024-03-01"));
+        assert_eq!(
+            Ok("a_b-c.txt".to_string()),
+            expand("{name}", "a_b-c.txt", "", &data)
+        );
+        assert_eq!(
+            Ok("README".to_string()),
+            expand("{stem}{ext}", "README", "", &data)
+        );
+        assert!(expand("{unknown}", "a.txt", "", &data).is_err());
+        assert!(expand("{name", "a.txt", "", &data).is_err());
+        assert!(expand("../{name}", "a.txt", "", &data).is_err());
+        assert!(expand("/{name}", "a.txt", "", &data).is_err());
+        assert!(expand("{category:Year}/", "a.txt", "", &data).is_err());
+
+        let layout = Layout::Template {
+            template: "{category:Year}/{name}".to_string(),
+        };
+        assert_eq!(Ok(()), layout.check());
+        let layout = Layout::Template {
+            template: "{year}/{name}".to_string(),
+        };
+        assert!(layout.check().is_err());
+    }
+
+    #[test]
+    fn template_metadata() {
+        let data = FileMetadata {
+            tags: vec!["taxes".to_string(), "work".to_string()],
+            categories: HashMap::from([
+                ("Year".to_string(), vec!["2024".to_string()]),
+                (
+                    "Sender".to_string(),
+                    vec!["ACME/Corp: \"Europe\"".to_string()],
+                ),
+            ]),
+            date: Some("2024-03-01".to_string()),
+        };
+        let template = "{category:Year}/{category:Sender}/{date}-{name}";
+        assert_eq!(
+            Ok("2024/ACME_Corp_ _Europe_/2024-03-01-letter.pdf".to_string()),
+            expand(template, "letter.pdf", "", &data)
+        );
+        assert_eq!(
+            Ok("taxes, work/letter.pdf".to_string()),
+            expand("{tags}/{name}", "letter.pdf", "", &data)
+        );
+
+        // Missing values use the fallback or leave out the folder
+        assert_eq!(
+            Ok("Unsorted/letter.pdf".to_string()),
+            expand("{category:Person|Unsorted}/{name}", "letter.pdf", "", &data)
+        );
+        assert_eq!(
+            Ok("2024/letter.pdf".to_string()),
+            expand(
+                "{category:Year}/{category:Person}/{name}",
+                "letter.pdf",
+                "",
+                &data
+            )
+        );
+        assert!(expand("{category}/{name}", "letter.pdf", "", &data).is_err());
+        assert!(expand("{tag:taxes}/{name}", "letter.pdf", "", &data).is_err());
+    }
+
+    #[test]
+    fn sanitize_names() {
+        assert_eq!("a_b_c", sanitize_value("a/b\\c"));
+        assert_eq!("what_ _yes_", sanitize_value("what? *yes\n"));
+        assert_eq!("notes", sanitize_component("notes. "));
+        assert_eq!("_", sanitize_component(".."));
+        assert_eq!("", sanitize_component("  "));
+        assert_eq!("_con.txt", sanitize_component("con.txt"));
+        assert_eq!("console.txt", sanitize_component("console.txt"));
+    }
+}
@@ -0,0 +1,19 @@
+//! Core of the files archive: configuration, database and import logic,
+//! shared by the desktop app and the command-line interface.
+
+pub mod bulk;
+pub mod config;
+pub mod db;
+pub mod error;
+mod extract;
+pub mod import;
+pub mod inbox;
+pub mod layout;
+mod migration;
+mod query;
+pub mod rules;
+pub mod tags;
+#[cfg(test)]
+mod test_util;
+pub mod transfer;
+pub mod vault;
@@ -1,143 +1,949 @@
 // Prevents additional console window on Windows in release, DO NOT REMOVE!!
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
-mod config;
-mod db;
-use config::Config;
-use db::Database;
-use log::{debug, info};
-use rand::{distributions::Alphanumeric, Rng};
+use files::bulk::{self, Selection};
+use files::config::{Config, VaultInfo, Vaults};
+use files::db::{
+    ConsistencyPlan, Database, FileEntry, FileMetadata, SearchQuery, SortOrder, TextHit,
+};
+use files::error::Error;
+use files::import;
+use files::inbox::{self, InboxWatcher};
+use files::rules::{self, RuleMatch};
+use files::transfer::ImportMode;
+use files::vault::{self, Problem, Report};
+use log::{debug, info, warn};
 use std::collections::HashMap;
-use std::path::Path;
-use std::sync::Mutex;
-use tauri::{Manager, State};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tauri::{AppHandle, Manager, State};
 
 #[tauri::command]
 fn load_config(
     config_state: State<Mutex<Option<Config>>>,
     db_state: State<Mutex<Database>>,
-) -> Result<Config, String> {
-    let config_opt = match config_state.lock() {
+) -> Result<Config, Error> {
+    let mut config_opt = match config_state.lock() {
         Ok(content) => content,
-        Err(_) => return Err("Locking the config mutex failed.".to_string()),
+        Err(_) => {
+            return Err(Error::Internal(
+                "Locking the config mutex failed.".to_string(),
+            ))
+        }
     };
-    if let Some(config) = config_opt.clone() {
+    if let Some(mut config) = config_opt.clone() {
         let db = match db_state.lock() {
             Ok(content) => content,
-            Err(_) => return Err("Locking the db mutex failed.".to_string()),
+            Err(_) => return Err(Error::Internal("Locking the db mutex failed.".to_string())),
         };
-        assert!(db.is_initialized());
-        db.check_config_consistency(&config)?;
+        if !db.is_initialized() {
+            return Err(Error::DatabaseClosed);
+        }
+        // Others may have changed the tags and categories of a shared vault
+        db.load_taxonomy(&mut config)?;
+        *config_opt = Some(config.clone());
         Ok(config)
     } else {
-        return Err("No config exists yet.".to_string());
+        return Err(Error::NotConfigured);
     }
 }
 
+#[tauri::command]
+fn plan_config(config: Config, db_state: State<Mutex<Database>>) -> Result<ConsistencyPlan, Error> {
+    let mut db = match db_state.lock() {
+        Ok(content) => content,
+        Err(_) => return Err(Error::Internal("Locking the db mutex failed.".to_string())),
+    };
+
+    if !db.is_initialized() {
+        match db.open(config.db_path()) {
+            Ok(_) => (),
+            Err(error) => return Err(error),
+        };
+    }
+
+    db.plan_config_consistency(&config)
+}
+
+/// Stores the config and updates the database accordingly. Removing tags,
+/// categories or values that are still used by files has to be `confirmed`
+/// with the plan shown to the user, it is refused if the plan changed since.
 #[tauri::command]
 fn store_config(
     config: Config,
+    confirmed: Option<ConsistencyPlan>,
+    app: AppHandle,
     config_state: State<Mutex<Option<Config>>>,
     db_state: State<Mutex<Database>>,
-) -> Result<(), String> {
+) -> Result<(), Error> {
     info!("Storing new config: {0:?}", config);
+    rules::check(&config)?;
+    config.layout.check()?;
     let mut db = match db_state.lock() {
         Ok(content) => content,
-        Err(_) => return Err("Locking the db mutex failed.".to_string()),
+        Err(_) => return Err(Error::Internal("Locking the db mutex failed.".to_string())),
     };
 
     if !db.is_initialized() {
         match db.open(config.db_path()) {
             Ok(_) => (),
-            Err(error) => return Err("Opening database failed: ".to_string() + &error.to_string()),
+            Err(error) => return Err(error),
         };
     }
 
-    db.check_config_consistency(&config)?;
+    let plan = db.plan_config_consistency(&config)?;
+    plan.check_confirmed(confirmed.as_ref())?;
+    db.apply_consistency_plan(&plan)?;
     config
         .store()
-        .expect("Failed to store config. But the database has been updated!");
-    let mut old_config = config_state.lock().unwrap();
+        .map_err(|err| err.context("Storing the config failed, but the database was updated"))?;
+    drop(db);
+    let mut old_config = match config_state.lock() {
+        Ok(content) => content,
+        Err(_) => {
+            return Err(Error::Internal(
+                "Locking the config mutex failed.".to_string(),
+            ))
+        }
+    };
+    let inbox_changed = old_config.as_ref().map(|c| &c.inbox) != Some(&config.inbox);
     *old_config = Some(config);
+    drop(old_config);
+    if inbox_changed {
+        watch_inbox(&app);
+    }
     Ok(())
 }
 
 #[tauri::command]
+fn list_vaults() -> Result<Vec<VaultInfo>, Error> {
+    Ok(Vaults::load()?.list())
+}
+
+/// Adds a vault with its own folder and database, without opening it. The
+/// folder may hold a vault already, e.g. one shared on a NAS.
+#[tauri::command]
+fn create_vault(name: String, mut config: Config) -> Result<(), Error> {
+    info!("Creating vault {name}: {config:?}");
+    rules::check(&config)?;
+    config.layout.check()?;
+    let mut vaults = Vaults::load()?;
+    vaults.add(&name, config.without_taxonomy())?;
+    vault::open(&mut config)?;
+    vaults.store()
+}
+
+/// Closes the database of the open vault and opens the one of the vault
+/// `name`, so files of different vaults never mix.
+#[tauri::command]
+fn open_vault(
+    name: String,
+    app: AppHandle,
+    config_state: State<Mutex<Option<Config>>>,
+    db_state: State<Mutex<Database>>,
+    cancel_state: State<Mutex<Option<Arc<AtomicBool>>>>,
+) -> Result<Config, Error> {
+    info!("Opening vault {name}");
+    // Held until the vault is switched, so no bulk import can start meanwhile
+    let cancel = match cancel_state.lock() {
+        Ok(content) => content,
+        Err(_) => {
+            return Err(Error::Internal(
+                "Locking the bulk import mutex failed.".to_string(),
+            ))
+        }
+    };
+    if cancel.is_some() {
+        return Err(Error::Conflict(
+            "A bulk import is still running.".to_string(),
+        ));
+    }
+
+    let mut vaults = Vaults::load()?;
+    let mut config = vaults.select(&name)?.clone();
+    let new_db = vault::open(&mut config)?;
+    vaults.set_current(config.without_taxonomy())?;
+    vaults.store()?;
+
+    let mut config_opt = match config_state.lock() {
+        Ok(content) => content,
+        Err(_) => {
+            return Err(Error::Internal(
+                "Locking the config mutex failed.".to_string(),
+            ))
+        }
+    };
+    let mut db = match db_state.lock() {
+        Ok(content) => content,
+        Err(_) => return Err(Error::Internal("Locking the db mutex failed.".to_string())),
+    };
+    *db = new_db;
+    *config_opt = Some(config.clone());
+    drop(db);
+    drop(config_opt);
+    drop(cancel);
+    watch_inbox(&app);
+    hash_missing(&app, config.folder());
+    Ok(config)
+}
+
+/// Applies a rename to both the config and the database. The database is
+/// renamed back if the config cannot be stored.
+fn rename(
+    config_state: State<Mutex<Option<Config>>>,
+    db_state: State<Mutex<Database>>,
+    rename_config: impl Fn(&mut Config) -> Result<(), Error>,
+    rename_db: impl Fn(&Database) -> Result<(), Error>,
+    undo_db: impl Fn(&Database) -> Result<(), Error>,
+) -> Result<Config, Error> {
+    let mut config_opt = match config_state.lock() {
+        Ok(content) => content,
+        Err(_) => {
+            return Err(Error::Internal(
+                "Locking the config mutex failed.".to_string(),
+            ))
+        }
+    };
+    let mut config = match config_opt.clone() {
+        Some(config) => config,
+        None => return Err(Error::NotConfigured),
+    };
+    let db = match db_state.lock() {
+        Ok(content) => content,
+        Err(_) => return Err(Error::Internal("Locking the db mutex failed.".to_string())),
+    };
+    if !db.is_initialized() {
+        return Err(Error::DatabaseClosed);
+    }
+
+    rename_config(&mut config)?;
+    rename_db(&db)?;
+    if let Err(error) = config.store() {
+        if let Err(err) = undo_db(&db) {
+            warn!("Undoing rename failed: {err}");
+        }
+        return Err(error.context("Storing config failed"));
+    }
+    *config_opt = Some(config.clone());
+    Ok(config)
+}
+
+#[tauri::command]
+fn rename_tag(
+    old: String,
+    new: String,
+    config_state: State<Mutex<Option<Config>>>,
+    db_state: State<Mutex<Database>>,
+) -> Result<Config, Error> {
+    rename(
+        config_state,
+        db_state,
+        |config| config.rename_tag(&old, &new),
+        |db| db.rename_tag(&old, &new),
+        |db| db.rename_tag(&new, &old),
+    )
+}
+
+/// Folds the tag `from` into `into`, which can not be undone. If the config
+/// can not be stored, rules and the inbox still work, as `from` is an alias
+/// of `into` now.
+#[tauri::command]
+fn merge_tags(
+    from: String,
+    into: String,
+    config_state: State<Mutex<Option<Config>>>,
+    db_state: State<Mutex<Database>>,
+) -> Result<Config, Error> {
+    rename(
+        config_state,
+        db_state,
+        |config| config.merge_tag(&from, &into),
+        |db| db.merge_tags(&from, &into),
+        |_| Ok(()),
+    )
+}
+
+#[tauri::command]
+fn rename_category(
+    old: String,
+    new: String,
+    config_state: State<Mutex<Option<Config>>>,
+    db_state: State<Mutex<Database>>,
+) -> Result<Config, Error> {
+    rename(
+        config_state,
+        db_state,
+        |config| config.rename_category(&old, &new),
+        |db| db.rename_category(&old, &new),
+        |db| db.rename_category(&new, &old),
+    )
+}
+
+#[tauri::command]
+fn rename_category_value(
+    category: String,
+    old: String,
+    new: String,
+    config_state: State<Mutex<Option<Config>>>,
+    db_state: State<Mutex<Database>>,
+) -> Result<Config, Error> {
+    rename(
+        config_state,
+        db_state,
+        |config| config.rename_category_value(&category, &old, &new),
+        |db| db.rename_category_value(&category, &old, &new),
+        |db| db.rename_category_value(&category, &new, &old),
+    )
+}
+
+/// Imports a single file. `mode` overrides the import mode of the config.
+#[tauri::command]
+#[allow(clippy::too_many_arguments)]
 fn import(
     path: String,
     tags: Vec<String>,
-    categories: HashMap<String, String>,
+    categories: HashMap<String, Vec<String>>,
+    date: Option<String>,
+    allow_duplicate: Option<bool>,
+    mode: Option<ImportMode>,
     config_state: State<Mutex<Option<Config>>>,
     db_state: State<Mutex<Database>>,
-) -> Result<(), String> {
+) -> Result<(), Error> {
     info!("Stroing file {:?}", path);
     debug!("Tags: {:?}", tags);
     debug!("Categories: {:?}", categories);
 
-    let target_path = config_state.lock().unwrap().as_ref().unwrap().folder();
-    let path = Path::new(&path);
-    let filename = path.file_name().unwrap().to_str().unwrap().to_string();
+    let (mut config, date) = match config_state.lock() {
+        Ok(content) => match content.as_ref() {
+            Some(config) => (config.clone(), import::document_date(config, date)?),
+            None => return Err(Error::NotConfigured),
+        },
+        Err(_) => {
+            return Err(Error::Internal(
+                "Locking the config mutex failed.".to_string(),
+            ))
+        }
+    };
+    if let Some(mode) = mode {
+        config.import_mode = mode;
+    }
+    let metadata = FileMetadata {
+        tags,
+        categories,
+        date,
+    };
 
-    let folder: String = rand::thread_rng()
-        .sample_iter(&Alphanumeric)
-        .take(7)
-        .map(char::from)
-        .collect();
+    let db = match db_state.lock() {
+        Ok(content) => content,
+        Err(_) => return Err(Error::Internal("Locking the db mutex failed".to_string())),
+    };
+    if !db.is_initialized() {
+        return Err(Error::DatabaseClosed);
+    }
 
-    let target_path = target_path.join(folder.clone());
+    let folder = import::import_file(
+        &db,
+        &config,
+        Path::new(&path),
+        &metadata,
+        allow_duplicate.unwrap_or(false),
+    )?;
+    info!("Imported {path} into {folder}");
+    Ok(())
+}
 
-    info!("New file location: {:?}", target_path);
+/// Imports the selected files of a folder in the background, with the same
+/// metadata for all files. Progress is reported by `bulk-import` events.
+/// Returns the number of selected files.
+#[tauri::command]
+#[allow(clippy::too_many_arguments)]
+fn bulk_import(
+    app: AppHandle,
+    selection: Selection,
+    tags: Vec<String>,
+    categories: HashMap<String, Vec<String>>,
+    date: Option<String>,
+    allow_duplicate: Option<bool>,
+    mode: Option<ImportMode>,
+    cancel_state: State<Mutex<Option<Arc<AtomicBool>>>>,
+) -> Result<usize, Error> {
+    info!("Bulk import of {:?}", selection);
+    let (mut config, date) = match app.state::<Mutex<Option<Config>>>().lock() {
+        Ok(content) => match content.as_ref() {
+            Some(config) => (config.clone(), import::document_date(config, date)?),
+            None => return Err(Error::NotConfigured),
+        },
+        Err(_) => {
+            return Err(Error::Internal(
+                "Locking the config mutex failed.".to_string(),
+            ))
+        }
+    };
+    if let Some(mode) = mode {
+        config.import_mode = mode;
+    }
+    let metadata = FileMetadata {
+        tags,
+        categories,
+        date,
+    };
+    let files = selection.files(&config.folder())?;
 
-    // Create target folder
-    std::fs::create_dir(&target_path).unwrap();
+    let cancel = Arc::new(AtomicBool::new(false));
+    match cancel_state.lock() {
+        Ok(mut running) => {
+            if running.is_some() {
+                return Err(Error::Conflict(
+                    "A bulk import is already running.".to_string(),
+                ));
+            }
+            *running = Some(cancel.clone());
+        }
+        Err(_) => {
+            return Err(Error::Internal(
+                "Locking the bulk import mutex failed.".to_string(),
+            ))
+        }
+    }
 
-    let target_path = target_path.join(filename.clone());
+    let total = files.len();
+    std::thread::spawn(move || {
+        let _running = BulkImportGuard(app.clone());
+        let db_state = app.state::<Mutex<Database>>();
+        bulk::import_files(
+            db_state.inner(),
+            &config,
+            &files,
+            &metadata,
+            allow_duplicate.unwrap_or(false),
+            &cancel,
+            |progress| {
+                if let Err(err) = app.emit_all("bulk-import", progress) {
+                    warn!("Sending bulk import event failed: {err}");
+                }
+            },
+        );
+    });
+    Ok(total)
+}
 
-    // Copying the file
-    match std::fs::copy(&path, &target_path) {
-        Ok(bytes) => debug!("Copied {bytes} to the new folder {:?}", target_path),
-        Err(error) => return Err("Importing file failed: ".to_string() + &error.to_string()),
+/// Marks the bulk import as finished when dropped, so a panicking import does
+/// not block later ones.
+struct BulkImportGuard(AppHandle);
+
+impl Drop for BulkImportGuard {
+    fn drop(&mut self) {
+        match self.0.state::<Mutex<Option<Arc<AtomicBool>>>>().lock() {
+            Ok(mut running) => *running = None,
+            Err(_) => warn!("Locking the bulk import mutex failed."),
+        }
     }
+}
 
+/// Stops the running bulk import after the current file.
+#[tauri::command]
+fn cancel_bulk_import(cancel_state: State<Mutex<Option<Arc<AtomicBool>>>>) -> Result<(), Error> {
+    match cancel_state.lock() {
+        Ok(running) => match running.as_ref() {
+            Some(cancel) => {
+                info!("Cancelling bulk import");
+                cancel.store(true, Ordering::Relaxed);
+                Ok(())
+            }
+            None => Err(Error::Conflict("No bulk import is running.".to_string())),
+        },
+        Err(_) => Err(Error::Internal(
+            "Locking the bulk import mutex failed.".to_string(),
+        )),
+    }
+}
+
+/// Groups of files with the same content. Hashes missing for older files are
+/// computed first.
+#[tauri::command]
+fn find_duplicates(
+    config_state: State<Mutex<Option<Config>>>,
+    db_state: State<Mutex<Database>>,
+) -> Result<Vec<Vec<FileEntry>>, Error> {
+    let vault = match config_state.lock() {
+        Ok(content) => match content.as_ref() {
+            Some(config) => config.folder(),
+            None => return Err(Error::NotConfigured),
+        },
+        Err(_) => {
+            return Err(Error::Internal(
+                "Locking the config mutex failed.".to_string(),
+            ))
+        }
+    };
     let db = match db_state.lock() {
         Ok(content) => content,
-        Err(_) => return Err("Locking the db mutex failed".to_string()),
+        Err(_) => return Err(Error::Internal("Locking the db mutex failed".to_string())),
     };
-    assert!(db.is_initialized());
+    if !db.is_initialized() {
+        return Err(Error::DatabaseClosed);
+    }
 
-    db.store_file(&folder, &filename)?;
+    vault::find_duplicates(&db, &vault)
+}
 
-    // Handle tags
-    for tag in tags {
-        db.associate_tag_with_file(&folder, &tag)?;
+/// Compares the database with the files in the vault.
+#[tauri::command]
+fn verify_vault(
+    check_hashes: Option<bool>,
+    config_state: State<Mutex<Option<Config>>>,
+    db_state: State<Mutex<Database>>,
+) -> Result<Report, Error> {
+    let vault = match config_state.lock() {
+        Ok(content) => match content.as_ref() {
+            Some(config) => config.folder(),
+            None => return Err(Error::NotConfigured),
+        },
+        Err(_) => {
+            return Err(Error::Internal(
+                "Locking the config mutex failed.".to_string(),
+            ))
+        }
+    };
+    let db = match db_state.lock() {
+        Ok(content) => content,
+        Err(_) => return Err(Error::Internal("Locking the db mutex failed".to_string())),
+    };
+    if !db.is_initialized() {
+        return Err(Error::DatabaseClosed);
     }
 
-    for (category, value) in categories {
-        db.associate_value_with_file(&folder, &category, &value)?;
+    vault::verify(&db, &vault, check_hashes.unwrap_or(false))
+}
+
+/// Repairs a problem reported by `verify_vault`.
+#[tauri::command]
+fn repair_vault(
+    problem: Problem,
+    config_state: State<Mutex<Option<Config>>>,
+    db_state: State<Mutex<Database>>,
+) -> Result<(), Error> {
+    info!("Repairing {:?}", problem);
+    let vault = match config_state.lock() {
+        Ok(content) => match content.as_ref() {
+            Some(config) => config.folder(),
+            None => return Err(Error::NotConfigured),
+        },
+        Err(_) => {
+            return Err(Error::Internal(
+                "Locking the config mutex failed.".to_string(),
+            ))
+        }
+    };
+    let db = match db_state.lock() {
+        Ok(content) => content,
+        Err(_) => return Err(Error::Internal("Locking the db mutex failed".to_string())),
+    };
+    if !db.is_initialized() {
+        return Err(Error::DatabaseClosed);
     }
+
+    vault::repair(&db, &vault, &problem)
+}
+
+/// Shows the tags and values the rules would add to the file at `path`.
+#[tauri::command]
+fn preview_rules(
+    path: String,
+    config_state: State<Mutex<Option<Config>>>,
+) -> Result<RuleMatch, Error> {
+    match config_state.lock() {
+        Ok(content) => match content.as_ref() {
+            Some(config) => rules::preview(&config.rules, Path::new(&path)),
+            None => Err(Error::NotConfigured),
+        },
+        Err(_) => Err(Error::Internal(
+            "Locking the config mutex failed.".to_string(),
+        )),
+    }
+}
+
+#[tauri::command]
+fn update_file_metadata(
+    path: String,
+    tags: Vec<String>,
+    categories: HashMap<String, Vec<String>>,
+    db_state: State<Mutex<Database>>,
+) -> Result<(), Error> {
+    debug!("Tags: {:?}", tags);
+    debug!("Categories: {:?}", categories);
+    let db = match db_state.lock() {
+        Ok(content) => content,
+        Err(_) => return Err(Error::Internal("Locking the db mutex failed".to_string())),
+    };
+    if !db.is_initialized() {
+        return Err(Error::DatabaseClosed);
+    }
+
+    db.update_file_metadata(&path, &tags, &categories)
+}
+
+#[tauri::command]
+fn delete_file(
+    path: String,
+    config_state: State<Mutex<Option<Config>>>,
+    db_state: State<Mutex<Database>>,
+) -> Result<(), Error> {
+    let vault = match config_state.lock() {
+        Ok(content) => match content.as_ref() {
+            Some(config) => config.folder(),
+            None => return Err(Error::NotConfigured),
+        },
+        Err(_) => {
+            return Err(Error::Internal(
+                "Locking the config mutex failed.".to_string(),
+            ))
+        }
+    };
+    let db = match db_state.lock() {
+        Ok(content) => content,
+        Err(_) => return Err(Error::Internal("Locking the db mutex failed".to_string())),
+    };
+    if !db.is_initialized() {
+        return Err(Error::DatabaseClosed);
+    }
+
+    db.delete_file(&vault, &path)
+}
+
+#[tauri::command]
+fn search(query: SearchQuery, db_state: State<Mutex<Database>>) -> Result<Vec<FileEntry>, Error> {
+    let db = match db_state.lock() {
+        Ok(content) => content,
+        Err(_) => return Err(Error::Internal("Locking the db mutex failed".to_string())),
+    };
+    if !db.is_initialized() {
+        return Err(Error::DatabaseClosed);
+    }
+
+    db.search(&query)
+}
+
+#[tauri::command]
+fn query(
+    query: String,
+    sort: Option<SortOrder>,
+    db_state: State<Mutex<Database>>,
+) -> Result<Vec<FileEntry>, Error> {
+    let db = match db_state.lock() {
+        Ok(content) => content,
+        Err(_) => return Err(Error::Internal("Locking the db mutex failed".to_string())),
+    };
+    if !db.is_initialized() {
+        return Err(Error::DatabaseClosed);
+    }
+
+    db.query(&query, sort.unwrap_or_default())
+}
+
+#[tauri::command]
+fn full_text_search(text: String, db_state: State<Mutex<Database>>) -> Result<Vec<TextHit>, Error> {
+    let db = match db_state.lock() {
+        Ok(content) => content,
+        Err(_) => return Err(Error::Internal("Locking the db mutex failed".to_string())),
+    };
+    if !db.is_initialized() {
+        return Err(Error::DatabaseClosed);
+    }
+
+    db.full_text_search(&text)
+}
+
+/// Imports a file of the inbox and tells the UI about it.
+fn process_inbox_file(app: &AppHandle, path: &Path) {
+    let config_state = app.state::<Mutex<Option<Config>>>();
+    let config = match config_state.lock() {
+        Ok(content) => match content.clone() {
+            Some(config) => config,
+            None => return,
+        },
+        Err(_) => {
+            warn!("Locking the config mutex failed.");
+            return;
+        }
+    };
+    let db_state = app.state::<Mutex<Database>>();
+    let db = match db_state.lock() {
+        Ok(content) => content,
+        Err(_) => {
+            warn!("Locking the db mutex failed");
+            return;
+        }
+    };
+    // The file may have been processed while waiting for the lock
+    if !db.is_initialized() || !inbox::is_candidate(path) {
+        return;
+    }
+
+    match inbox::process_file(&db, &config, path) {
+        Ok(outcome) => {
+            if let Err(err) = app.emit_all("inbox", outcome) {
+                warn!("Sending inbox event failed: {err}");
+            }
+        }
+        Err(err) => warn!("Processing {:?} from the inbox failed: {err}", path),
+    }
+}
+
+/// Runs `action` on the database of the vault in `folder`, unless another
+/// vault was opened meanwhile.
+fn with_vault_db<T>(
+    app: &AppHandle,
+    folder: &Path,
+    action: impl FnOnce(&Database) -> Result<T, Error>,
+) -> Result<Option<T>, Error> {
+    let config_state = app.state::<Mutex<Option<Config>>>();
+    // Held while the database is used, as the vault is switched under both
+    let config = match config_state.lock() {
+        Ok(content) => content,
+        Err(_) => {
+            return Err(Error::Internal(
+                "Locking the config mutex failed.".to_string(),
+            ))
+        }
+    };
+    if config.as_ref().map(|config| config.folder()).as_deref() != Some(folder) {
+        return Ok(None);
+    }
+    let db_state = app.state::<Mutex<Database>>();
+    let db = match db_state.lock() {
+        Ok(content) => content,
+        Err(_) => return Err(Error::Internal("Locking the db mutex failed.".to_string())),
+    };
+    action(&db).map(Some)
+}
+
+/// Stores the hashes of files imported before hashes were introduced in the
+/// background. The database is only locked to read and store the hashes, not
+/// while the files are read.
+fn hash_missing(app: &AppHandle, folder: PathBuf) {
+    let handle = app.clone();
+    std::thread::spawn(move || {
+        let files = match with_vault_db(&handle, &folder, |db| db.files_without_hash()) {
+            Ok(Some(files)) => files,
+            Ok(None) => return,
+            Err(err) => {
+                warn!("{err}");
+                return;
+            }
+        };
+        let mut hashed = 0;
+        for file in files {
+            let hash = match vault::hash_file(&file.location(&folder)) {
+                Ok(hash) => hash,
+                Err(err) => {
+                    warn!("{err}");
+                    continue;
+                }
+            };
+            match with_vault_db(&handle, &folder, |db| db.set_file_hash(&file.path, &hash)) {
+                Ok(Some(())) => hashed += 1,
+                // Another vault was opened
+                Ok(None) => break,
+                Err(err) => warn!("{err}"),
+            }
+        }
+        if hashed > 0 {
+            info!("Stored the hashes of {hashed} files");
+        }
+    });
+}
+
+/// Starts watching the inbox of the current config, replacing the previous
+/// watcher. Files that arrived while the inbox was not watched are processed
+/// in the background.
+fn watch_inbox(app: &AppHandle) {
+    let watcher_state = app.state::<Mutex<Option<InboxWatcher>>>();
+    let mut watcher = match watcher_state.lock() {
+        Ok(content) => content,
+        Err(_) => {
+            warn!("Locking the inbox mutex failed.");
+            return;
+        }
+    };
+    *watcher = None;
+
+    let config = match app.state::<Mutex<Option<Config>>>().lock() {
+        Ok(content) => content.clone(),
+        Err(_) => {
+            warn!("Locking the config mutex failed.");
+            return;
+        }
+    };
+    let config = match config {
+        Some(config) if config.inbox.is_some() => config,
+        _ => return,
+    };
+    let folder = config.inbox.as_ref().unwrap().folder.clone();
+
+    let handle = app.clone();
+    match inbox::watch(Path::new(&folder), move |path| {
+        process_inbox_file(&handle, path)
+    }) {
+        Ok(new_watcher) => *watcher = Some(new_watcher),
+        Err(err) => {
+            warn!("{err}");
+            return;
+        }
+    }
+
+    let handle = app.clone();
+    std::thread::spawn(move || match inbox::files(&config) {
+        Ok(files) => {
+            for path in files {
+                process_inbox_file(&handle, &path);
+            }
+        }
+        Err(err) => warn!("{err}"),
+    });
+}
+
+#[tauri::command]
+fn list_pending(config_state: State<Mutex<Option<Config>>>) -> Result<Vec<String>, Error> {
+    match config_state.lock() {
+        Ok(content) => match content.as_ref() {
+            Some(config) if config.inbox.is_some() => inbox::list_pending(config),
+            Some(_) => Ok(Vec::new()),
+            None => Err(Error::NotConfigured),
+        },
+        Err(_) => Err(Error::Internal(
+            "Locking the config mutex failed.".to_string(),
+        )),
+    }
+}
+
+#[tauri::command]
+fn import_pending(
+    name: String,
+    tags: Vec<String>,
+    categories: HashMap<String, Vec<String>>,
+    date: Option<String>,
+    allow_duplicate: Option<bool>,
+    config_state: State<Mutex<Option<Config>>>,
+    db_state: State<Mutex<Database>>,
+) -> Result<(), Error> {
+    let config = match config_state.lock() {
+        Ok(content) => match content.clone() {
+            Some(config) => config,
+            None => return Err(Error::NotConfigured),
+        },
+        Err(_) => {
+            return Err(Error::Internal(
+                "Locking the config mutex failed.".to_string(),
+            ))
+        }
+    };
+    let metadata = FileMetadata {
+        tags,
+        categories,
+        date: import::document_date(&config, date)?,
+    };
+
+    let db = match db_state.lock() {
+        Ok(content) => content,
+        Err(_) => return Err(Error::Internal("Locking the db mutex failed".to_string())),
+    };
+    if !db.is_initialized() {
+        return Err(Error::DatabaseClosed);
+    }
+
+    let folder = inbox::import_pending(
+        &db,
+        &config,
+        &name,
+        &metadata,
+        allow_duplicate.unwrap_or(false),
+    )?;
+    info!("Imported pending {name} into {folder}");
     Ok(())
 }
 
+#[tauri::command]
+fn discard_pending(name: String, config_state: State<Mutex<Option<Config>>>) -> Result<(), Error> {
+    match config_state.lock() {
+        Ok(content) => match content.as_ref() {
+            Some(config) => inbox::discard_pending(config, &name),
+            None => Err(Error::NotConfigured),
+        },
+        Err(_) => Err(Error::Internal(
+            "Locking the config mutex failed.".to_string(),
+        )),
+    }
+}
+
 fn main() {
     env_logger::init();
     tauri::Builder::default()
         .setup(|app| {
-            let mut db = Database::new();
             // If we can load a config, we will load the database too
-            match Config::load() {
-                Ok(config) => {
-                    db.open(config.db_path())
-                        .expect("Opening the database failed");
+            let opened = Config::load().and_then(|mut config| {
+                let moved = config.has_taxonomy();
+                let db = vault::open(&mut config)?;
+                // The tags and categories are in the vault now
+                if moved {
+                    if let Err(err) = config.store() {
+                        warn!("{err}");
+                    }
+                }
+                Ok((config, db))
+            });
+            let (db, folder) = match opened {
+                Ok((config, db)) => {
+                    let folder = config.folder();
                     app.manage(Mutex::new(Some(config)));
+                    (db, Some(folder))
                 }
-                Err(_error) => {
+                Err(err) => {
+                    // Starts unconfigured, so another vault can be chosen
+                    if !matches!(err, Error::NotConfigured) {
+                        warn!("Opening the vault failed: {err}");
+                    }
                     app.manage(Mutex::new(None::<Config>));
+                    (Database::new(), None)
                 }
-            }
+            };
             app.manage(Mutex::new(db));
+            app.manage(Mutex::new(None::<InboxWatcher>));
+            app.manage(Mutex::new(None::<Arc<AtomicBool>>));
+            watch_inbox(&app.handle());
+            if let Some(folder) = folder {
+                hash_missing(&app.handle(), folder);
+            }
             Ok(())
         })
-        .invoke_handler(tauri::generate_handler![load_config, store_config, import])
+        .invoke_handler(tauri::generate_handler![
+            load_config,
+            plan_config,
+            store_config,
+            rename_tag,
+            merge_tags,
+            rename_category,
+            rename_category_value,
+            list_vaults,
+            create_vault,
+            open_vault,
+            import,
+            bulk_import,
+            cancel_bulk_import,
+            preview_rules,
+            find_duplicates,
+            verify_vault,
+            repair_vault,
+            update_file_metadata,
+            delete_file,
+            search,
+            query,
+            full_text_search,
+            list_pending,
+            import_pending,
+            discard_pending
+        ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
 }
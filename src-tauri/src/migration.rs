@@ -0,0 +1,367 @@
+use log::{debug, info};
+use rusqlite::{params, Connection, Result};
+use std::path::{Path, PathBuf};
+
+type Migration = fn(&Connection) -> Result<()>;
+
+/// Schema migrations, the migration at index `i` brings the database from
+/// version `i` to `i + 1`. Never change a migration once it is released, add
+/// a new one instead.
+const MIGRATIONS: &[Migration] = &[
+    initial_schema,
+    add_dates,
+    add_file_contents,
+    add_hashes,
+    add_layouts,
+    add_tag_parents,
+    add_tag_aliases,
+    add_multiple_values,
+];
+
+/// The schema version of a fully migrated database.
+pub const VERSION: u32 = MIGRATIONS.len() as u32;
+
+/// The schema before versioning was introduced. Vaults created back then are
+/// at version 0 but already contain these tables.
+fn initial_schema(con: &Connection) -> Result<()> {
+    con.execute_batch(
+        "
+CREATE TABLE IF NOT EXISTS files (
+  id INTEGER PRIMARY KEY,
+  path TEXT NOT NULL UNIQUE,
+  name TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS tags (
+  id INTEGER PRIMARY KEY,
+  tag TEXT NOT NULL UNIQUE
+);
+
+CREATE TABLE IF NOT EXISTS fileTags (
+  file_id INTEGER,
+  tag_id INTEGER,
+  CONSTRAINT fk_file FOREIGN KEY (file_id) REFERENCES files(id) ON UPDATE CASCADE ON DELETE CASCADE,
+  CONSTRAINT fk_tag FOREIGN KEY (tag_id) REFERENCES tags(id) ON UPDATE CASCADE ON DELETE CASCADE
+);
+
+CREATE TABLE IF NOT EXISTS categories (
+  id INTEGER PRIMARY KEY,
+  name TEXT NOT NULL UNIQUE
+);
+
+CREATE TABLE IF NOT EXISTS categoryValue (
+  category_id INTEGER,
+  value TEXT NOT NULL,
+  id TEXT GENERATED ALWAYS AS (concat(value, category_id)) VIRTUAL UNIQUE,
+  CONSTRAINT fk_category FOREIGN KEY (category_id) REFERENCES categories(id) ON UPDATE CASCADE ON DELETE CASCADE,
+  CONSTRAINT cv PRIMARY KEY (category_id, value)
+);
+
+
+CREATE TABLE IF NOT EXISTS fileValues (
+  file_id INTEGER,
+  value_id TEXT,
+  CONSTRAINT fk_file FOREIGN KEY (file_id) REFERENCES files(id) ON UPDATE CASCADE ON DELETE CASCADE,
+  CONSTRAINT fk_value FOREIGN KEY (value_id) REFERENCES categoryValue(id) ON UPDATE CASCADE ON DELETE CASCADE
+);",
+    )
+}
+
+fn has_column(con: &Connection, table: &str, column: &str) -> Result<bool> {
+    let columns = con
+        .prepare("SELECT name FROM pragma_table_info(?1)")?
+        .query_map(params![table], |row| row.get(0))?
+        .collect::<Result<Vec<String>>>()?;
+    Ok(columns.iter().any(|c| c == column))
+}
+
+fn add_dates(con: &Connection) -> Result<()> {
+    // Development builds created the columns without a schema version
+    if has_column(con, "files", "date")? {
+        return Ok(());
+    }
+    con.execute_batch(
+        "
+ALTER TABLE files ADD COLUMN date TEXT;
+ALTER TABLE files ADD COLUMN imported_at INTEGER;",
+    )
+}
+
+fn add_file_contents(con: &Connection) -> Result<()> {
+    con.execute_batch(
+        "
+CREATE VIRTUAL TABLE IF NOT EXISTS fileContents USING fts5(content);
+
+-- Virtual tables do not support foreign keys
+CREATE TRIGGER IF NOT EXISTS fileContentsDelete AFTER DELETE ON files BEGIN
+  DELETE FROM fileContents WHERE rowid = old.id;
+END;",
+    )
+}
+
+fn add_hashes(con: &Connection) -> Result<()> {
+    con.execute_batch(
+        "
+ALTER TABLE files ADD COLUMN hash TEXT;
+CREATE INDEX IF NOT EXISTS filesHash ON files(hash);",
+    )
+}
+
+fn add_layouts(con: &Connection) -> Result<()> {
+    con.execute_batch("ALTER TABLE files ADD COLUMN layout TEXT NOT NULL DEFAULT 'random';")
+}
+
+/// Tags form a tree by their names. Missing ancestors of existing tags, like
+/// `finance` for `finance/tax`, are created.
+fn add_tag_parents(con: &Connection) -> Result<()> {
+    con.execute_batch(
+        "
+ALTER TABLE tags ADD COLUMN parent_id INTEGER REFERENCES tags(id) ON UPDATE CASCADE ON DELETE CASCADE;
+CREATE INDEX IF NOT EXISTS tagsParent ON tags(parent_id);",
+    )?;
+    let tags = con
+        .prepare("SELECT tag FROM tags WHERE tag LIKE '%/%'")?
+        .query_map([], |row| row.get(0))?
+        .collect::<Result<Vec<String>>>()?;
+    for tag in tags {
+        // Names like `a//b` stay at the root
+        if tag.split('/').any(|name| name.trim().is_empty()) {
+            continue;
+        }
+        let mut parent: Option<u32> = None;
+        let mut path = String::new();
+        for name in tag.split('/') {
+            if !path.is_empty() {
+                path.push('/');
+            }
+            path.push_str(name);
+            con.execute("INSERT OR IGNORE INTO tags(tag) VALUES (?1)", params![path])?;
+            con.execute(
+                "UPDATE tags SET parent_id = ?2 WHERE tag = ?1",
+                params![path, parent],
+            )?;
+            parent = Some(con.query_row(
+                "SELECT id FROM tags WHERE tag = ?1",
+                params![path],
+                |r| r.get(0),
+            )?);
+        }
+    }
+    Ok(())
+}
+
+/// An alias is a row of `tags` pointing at the tag it stands for, so tags and
+/// aliases share their names.
+fn add_tag_aliases(con: &Connection) -> Result<()> {
+    con.execute_batch(
+        "ALTER TABLE tags ADD COLUMN canonical_id INTEGER REFERENCES tags(id) ON UPDATE CASCADE ON DELETE CASCADE;",
+    )
+}
+
+/// Categories can hold several values per file, but never the same value
+/// twice.
+fn add_multiple_values(con: &Connection) -> Result<()> {
+    con.execute_batch(
+        "
+ALTER TABLE categories ADD COLUMN multiple INTEGER NOT NULL DEFAULT 0;
+DELETE FROM fileValues WHERE rowid NOT IN (SELECT MIN(rowid) FROM fileValues GROUP BY file_id, value_id);
+CREATE UNIQUE INDEX fileValuesUnique ON fileValues(file_id, value_id);",
+    )
+}
+
+pub fn version(con: &Connection) -> Result<u32> {
+    con.query_row("PRAGMA user_version", [], |r| r.get(0))
+}
+
+fn is_empty(con: &Connection) -> Result<bool> {
+    let count: u32 = con.query_row("SELECT COUNT(*) FROM sqlite_master", [], |r| r.get(0))?;
+    Ok(count == 0)
+}
+
+/// Copies the database next to `path` before it is migrated, so a vault can be
+/// restored if a migration goes wrong.
+fn backup(con: &Connection, path: &Path, version: u32) -> Result<PathBuf> {
+    let timestamp = chrono::Local::now().format("%Y%m%d%H%M%S");
+    let name = match path.file_name().and_then(|name| name.to_str()) {
+        Some(name) => format!("{name}.v{version}-{timestamp}.bak"),
+        None => format!("files.sqlite.v{version}-{timestamp}.bak"),
+    };
+    let backup = path.with_file_name(name);
+    info!("Backing up database to {}", backup.display());
+    con.execute("VACUUM INTO ?1", params![backup.to_string_lossy()])?;
+    Ok(backup)
+}
+
+/// Brings the database to the latest schema version. Each migration runs in
+/// its own transaction. If `path` is given and the database already contains
+/// data, a backup is created before the first migration.
+pub fn migrate(con: &Connection, path: Option<&Path>) -> Result<()> {
+    migrate_to(con, path, VERSION)
+}
+
+fn migrate_to(con: &Connection, path: Option<&Path>, target: u32) -> Result<()> {
+    let current = version(con)?;
+    if current > VERSION {
+        return Err(rusqlite::Error::SqliteFailure(
+            rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_ERROR),
+            Some(format!(
+                "Database version {current} is newer than the supported version {VERSION}"
+            )),
+        ));
+    }
+    if current >= target {
+        debug!("Database is at version {current}");
+        return Ok(());
+    }
+
+    if let Some(path) = path {
+        if !is_empty(con)? {
+            backup(con, path, current)?;
+        }
+    }
+
+    for (version, migration) in MIGRATIONS
+        .iter()
+        .enumerate()
+        .skip(current as usize)
+        .take((target - current) as usize)
+    {
+        info!("Migrating database to version {}", version + 1);
+        let tx = con.unchecked_transaction()?;
+        migration(&tx)?;
+        tx.pragma_update(None, "user_version", version as u32 + 1)?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    fn columns(con: &Connection, table: &str) -> Vec<String> {
+        con.prepare("SELECT name FROM pragma_table_info(?1)")
+            .unwrap()
+            .query_map(params![table], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<Vec<String>>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn migrate_new_database() {
+        let con = Connection::open_in_memory().expect("Database in memory failed");
+        migrate(&con, None).expect("Migration failed");
+        assert_eq!(VERSION, version(&con).unwrap());
+        assert!(columns(&con, "files").contains(&"date".to_string()));
+
+        // Migrating again does nothing
+        migrate(&con, None).expect("Migration failed");
+        assert_eq!(VERSION, version(&con).unwrap());
+    }
+
+    #[test]
+    fn migrate_step_by_step() {
+        let con = Connection::open_in_memory().expect("Database in memory failed");
+        for target in 1..=VERSION {
+            migrate_to(&con, None, target).expect("Migration failed");
+            assert_eq!(target, version(&con).unwrap());
+        }
+        assert_eq!(
+            vec![
+                "id",
+                "path",
+                "name",
+                "date",
+                "imported_at",
+                "hash",
+                "layout"
+            ],
+            columns(&con, "files")
+        );
+    }
+
+    #[test]
+    fn migrate_unversioned_vault() {
+        let con = Connection::open_in_memory().expect("Database in memory failed");
+        initial_schema(&con).expect("Failed to create old schema");
+        con.execute_batch(
+            "INSERT INTO files(path, name) VALUES ('aaaaaaa', 'a.pdf');
+             INSERT INTO tags(tag) VALUES ('taxes');
+             INSERT INTO tags(tag) VALUES ('finance/tax/2024');
+             INSERT INTO fileTags(file_id, tag_id) VALUES (1, 1);",
+        )
+        .expect("Failed to insert data");
+        assert_eq!(0, version(&con).unwrap());
+
+        migrate(&con, None).expect("Migration failed");
+        assert_eq!(VERSION, version(&con).unwrap());
+        let (name, date): (String, Option<String>) = con
+            .query_row("SELECT name, date FROM files", [], |r| {
+                Ok((r.get(0)?, r.get(1)?))
+            })
+            .unwrap();
+        assert_eq!(("a.pdf".to_string(), None), (name, date));
+        let links: u32 = con
+            .query_row("SELECT COUNT(*) FROM fileTags", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(1, links);
+        let parents = con
+            .prepare("SELECT tags.tag, parents.tag FROM tags LEFT JOIN tags AS parents ON tags.parent_id = parents.id ORDER BY tags.tag")
+            .unwrap()
+            .query_map([], |r| Ok((r.get(0)?, r.get(1)?)))
+            .unwrap()
+            .collect::<Result<Vec<(String, Option<String>)>>>()
+            .unwrap();
+        assert_eq!(
+            vec![
+                ("finance".to_string(), None),
+                ("finance/tax".to_string(), Some("finance".to_string())),
+                (
+                    "finance/tax/2024".to_string(),
+                    Some("finance/tax".to_string())
+                ),
+                ("taxes".to_string(), None)
+            ],
+            parents
+        );
+    }
+
+    #[test]
+    fn migrate_newer_database() {
+        let con = Connection::open_in_memory().expect("Database in memory failed");
+        con.pragma_update(None, "user_version", VERSION + 1)
+            .unwrap();
+        assert!(migrate(&con, None).is_err());
+    }
+
+    #[test]
+    fn migrate_creates_backup() {
+        let dir = TempDir::new("migration");
+        let path = dir.join("files.sqlite");
+
+        // A new database needs no backup
+        let con = Connection::open(&path).unwrap();
+        migrate_to(&con, Some(&path), 1).expect("Migration failed");
+        assert_eq!(1, std::fs::read_dir(&dir).unwrap().count());
+
+        migrate(&con, Some(&path)).expect("Migration failed");
+        let backups: Vec<PathBuf> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .filter(|p| p.to_string_lossy().ends_with(".bak"))
+            .collect();
+        assert_eq!(1, backups.len());
+        assert!(backups[0]
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .starts_with("files.sqlite.v1-"));
+        let backup = Connection::open(&backups[0]).unwrap();
+        assert_eq!(1, version(&backup).unwrap());
+
+        drop(backup);
+        drop(con);
+    }
+}
@@ -0,0 +1,540 @@
+use std::fmt;
+
+/// A parsed search expression, e.g.
+/// `tag:tax AND (Year=2023 OR Year=2024) AND NOT tag:draft name:*invoice*`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Tag(String),
+    Value(String, String),
+    /// Glob pattern on the file name, `*` and `?` are wildcards.
+    Name(String),
+    /// Inclusive range of document dates, either bound can be open.
+    Date(Option<String>, Option<String>),
+    Not(Box<Expr>),
+    /// Kept flat, so long queries do not nest deeply.
+    And(Vec<Expr>),
+    Or(Vec<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub position: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at position {}", self.message, self.position)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[derive(Debug, Clone, PartialEq)]
+enum TokenKind {
+    LParen,
+    RParen,
+    Colon,
+    Equals,
+    And,
+    Or,
+    Not,
+    Word(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Token {
+    kind: TokenKind,
+    position: usize,
+}
+
+impl TokenKind {
+    fn describe(&self) -> String {
+        match self {
+            TokenKind::LParen => "'('".to_string(),
+            TokenKind::RParen => "')'".to_string(),
+            TokenKind::Colon => "':'".to_string(),
+            TokenKind::Equals => "'='".to_string(),
+            TokenKind::And => "'AND'".to_string(),
+            TokenKind::Or => "'OR'".to_string(),
+            TokenKind::Not => "'NOT'".to_string(),
+            TokenKind::Word(word) => format!("'{word}'"),
+        }
+    }
+}
+
+fn is_word_char(c: char) -> bool {
+    !c.is_whitespace() && !matches!(c, '(' | ')' | ':' | '=' | '"')
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>, ParseError> {
+    let mut tokens = Vec::new();
+    let mut chars = input.char_indices().peekable();
+
+    while let Some(&(position, c)) = chars.peek() {
+        let kind = match c {
+            c if c.is_whitespace() => {
+                chars.next();
+                continue;
+            }
+            '(' => {
+                chars.next();
+                TokenKind::LParen
+            }
+            ')' => {
+                chars.next();
+                TokenKind::RParen
+            }
+            ':' => {
+                chars.next();
+                TokenKind::Colon
+            }
+            '=' => {
+                chars.next();
+                TokenKind::Equals
+            }
+            '"' => {
+                chars.next();
+                let mut word = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, '"')) => break,
+                        Some((_, '\\')) => match chars.next() {
+                            Some((_, escaped)) => word.push(escaped),
+                            None => break,
+                        },
+                        Some((_, c)) => word.push(c),
+                        None => {
+                            return Err(ParseError {
+                                position,
+                                message: "Unterminated string".to_string(),
+                            })
+                        }
+                    }
+                }
+                TokenKind::Word(word)
+            }
+            _ => {
+                let mut word = String::new();
+                while let Some(&(_, c)) = chars.peek() {
+                    if !is_word_char(c) {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                match word.as_str() {
+                    "AND" => TokenKind::And,
+                    "OR" => TokenKind::Or,
+                    "NOT" => TokenKind::Not,
+                    _ => TokenKind::Word(word),
+                }
+            }
+        };
+        tokens.push(Token { kind, position });
+    }
+
+    Ok(tokens)
+}
+
+/// How deep parentheses and `NOT`s can be nested, deeper queries would
+/// overflow the stack.
+const MAX_DEPTH: usize = 100;
+
+struct Parser {
+    tokens: Vec<Token>,
+    index: usize,
+    end: usize,
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&TokenKind> {
+        self.tokens.get(self.index).map(|t| &t.kind)
+    }
+
+    fn position(&self) -> usize {
+        self.tokens
+            .get(self.index)
+            .map(|t| t.position)
+            .unwrap_or(self.end)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.index).cloned();
+        self.index += 1;
+        token
+    }
+
+    fn unexpected(&self, expected: &str) -> ParseError {
+        let found = match self.peek() {
+            Some(kind) => kind.describe(),
+            None => "end of query".to_string(),
+        };
+        ParseError {
+            position: self.position(),
+            message: format!("Expected {expected}, found {found}"),
+        }
+    }
+
+    /// Runs `parse` one nesting level deeper.
+    fn nested(
+        &mut self,
+        parse: impl FnOnce(&mut Self) -> Result<Expr, ParseError>,
+    ) -> Result<Expr, ParseError> {
+        if self.depth == MAX_DEPTH {
+            return Err(ParseError {
+                position: self.position(),
+                message: format!("Query is nested deeper than {MAX_DEPTH} levels"),
+            });
+        }
+        self.depth += 1;
+        let expr = parse(self);
+        self.depth -= 1;
+        expr
+    }
+
+    fn parse_or(&mut self) -> Result<Expr, ParseError> {
+        let mut exprs = vec![self.parse_and()?];
+        while self.peek() == Some(&TokenKind::Or) {
+            self.next();
+            exprs.push(self.parse_and()?);
+        }
+        Ok(Expr::any(exprs).expect("At least one expression was parsed"))
+    }
+
+    fn parse_and(&mut self) -> Result<Expr, ParseError> {
+        let mut exprs = vec![self.parse_not()?];
+        loop {
+            match self.peek() {
+                Some(TokenKind::And) => {
+                    self.next();
+                }
+                // Juxtaposed terms are implicitly joined with AND
+                Some(TokenKind::Not) | Some(TokenKind::LParen) | Some(TokenKind::Word(_)) => (),
+                _ => break,
+            }
+            exprs.push(self.parse_not()?);
+        }
+        Ok(Expr::all(exprs).expect("At least one expression was parsed"))
+    }
+
+    fn parse_not(&mut self) -> Result<Expr, ParseError> {
+        if self.peek() == Some(&TokenKind::Not) {
+            self.next();
+            let expr = self.nested(Self::parse_not)?;
+            return Ok(Expr::Not(Box::new(expr)));
+        }
+        self.parse_primary()
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr, ParseError> {
+        match self.peek() {
+            Some(TokenKind::LParen) => {
+                self.next();
+                let expr = self.nested(Self::parse_or)?;
+                if self.peek() != Some(&TokenKind::RParen) {
+                    return Err(self.unexpected("')'"));
+                }
+                self.next();
+                Ok(expr)
+            }
+            Some(TokenKind::Word(_)) => self.parse_term(),
+            _ => Err(self.unexpected("a term")),
+        }
+    }
+
+    fn parse_term(&mut self) -> Result<Expr, ParseError> {
+        let word = match self.next() {
+            Some(Token {
+                kind: TokenKind::Word(word),
+                ..
+            }) => word,
+            _ => unreachable!("parse_term is only called on words"),
+        };
+        match self.peek() {
+            Some(TokenKind::Colon) => {
+                let field_position = self.tokens[self.index - 1].position;
+                self.next();
+                let value_position = self.position();
+                let value = self.parse_word()?;
+                match word.as_str() {
+                    "tag" => Ok(Expr::Tag(value)),
+                    "name" => Ok(Expr::Name(value)),
+                    "date" => parse_date_range(&value, value_position),
+                    _ => Err(ParseError {
+                        position: field_position,
+                        message: format!(
+                            "Unknown field '{word}', expected 'tag', 'name' or 'date'"
+                        ),
+                    }),
+                }
+            }
+            Some(TokenKind::Equals) => {
+                self.next();
+                let value = self.parse_word()?;
+                Ok(Expr::Value(word, value))
+            }
+            _ => Err(self.unexpected("':' or '='")),
+        }
+    }
+
+    fn parse_word(&mut self) -> Result<String, ParseError> {
+        match self.peek() {
+            Some(TokenKind::Word(word)) => {
+                let word = word.clone();
+                self.next();
+                Ok(word)
+            }
+            _ => Err(self.unexpected("a value")),
+        }
+    }
+}
+
+/// Parses a date (`2024-01-31`) or a date range (`2024-01-01..2024-12-31`,
+/// `2024-01-01..`, `..2024-12-31`).
+fn parse_date_range(value: &str, position: usize) -> Result<Expr, ParseError> {
+    let (from, to) = match value.split_once("..") {
+        Some((from, to)) => (from, to),
+        None => (value, value),
+    };
+    let check = |date: &str| -> Result<Option<String>, ParseError> {
+        if date.is_empty() {
+            return Ok(None);
+        }
+        match chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d") {
+            Ok(_) => Ok(Some(date.to_string())),
+            Err(_) => Err(ParseError {
+                position,
+                message: format!("Invalid date '{date}', expected YYYY-MM-DD"),
+            }),
+        }
+    };
+    Ok(Expr::Date(check(from)?, check(to)?))
+}
+
+/// Parses a query string into an expression. Terms are `tag:<tag>`,
+/// `name:<pattern>`, `date:<from>..<to>` and `<category>=<value>`; they can
+/// be combined with `AND`, `OR`, `NOT` and parentheses. Terms next to each
+/// other are joined with `AND`. Values containing special characters can be
+/// quoted.
+pub fn parse(input: &str) -> Result<Expr, ParseError> {
+    let mut parser = Parser {
+        tokens: tokenize(input)?,
+        index: 0,
+        end: input.len(),
+        depth: 0,
+    };
+    let expr = parser.parse_or()?;
+    if parser.peek().is_some() {
+        return Err(parser.unexpected("end of query"));
+    }
+    Ok(expr)
+}
+
+fn glob_to_like(pattern: &str) -> String {
+    let mut like = String::new();
+    for c in pattern.chars() {
+        match c {
+            '*' => like.push('%'),
+            '?' => like.push('_'),
+            '%' | '_' | '\\' => {
+                like.push('\\');
+                like.push(c);
+            }
+            _ => like.push(c),
+        }
+    }
+    like
+}
+
+/// Selects the id of the tag named by parameter `param`, or the tag an alias
+/// of that name stands for, and the ids of all its descendants.
+pub fn tag_subtree(param: usize) -> String {
+    format!("WITH RECURSIVE subtree(id) AS (SELECT COALESCE(canonical_id, id) FROM tags WHERE tag = ?{param} UNION ALL SELECT tags.id FROM tags JOIN subtree ON tags.parent_id = subtree.id) SELECT id FROM subtree")
+}
+
+impl Expr {
+    /// Joins the expressions with AND, `None` if there are none.
+    pub fn all(mut exprs: Vec<Expr>) -> Option<Expr> {
+        match exprs.len() {
+            0 => None,
+            1 => exprs.pop(),
+            _ => Some(Expr::And(exprs)),
+        }
+    }
+
+    /// Joins the expressions with OR, `None` if there are none.
+    pub fn any(mut exprs: Vec<Expr>) -> Option<Expr> {
+        match exprs.len() {
+            0 => None,
+            1 => exprs.pop(),
+            _ => Some(Expr::Or(exprs)),
+        }
+    }
+
+    /// Compiles the expression into an SQL condition on the `files` table.
+    /// Parameters are numbered starting after `params.len()`. A tag also
+    /// matches files with one of its descendants.
+    pub fn compile(&self, params: &mut Vec<String>) -> String {
+        match self {
+            Expr::Tag(tag) => {
+                params.push(tag.clone());
+                format!(
+                    "files.id IN (SELECT fileTags.file_id FROM fileTags WHERE fileTags.tag_id IN ({}))",
+                    tag_subtree(params.len())
+                )
+            }
+            Expr::Value(category, value) => {
+                params.push(category.clone());
+                params.push(value.clone());
+                format!("files.id IN (SELECT fileValues.file_id FROM fileValues JOIN categoryValue ON fileValues.value_id = categoryValue.id JOIN categories ON categoryValue.category_id = categories.id WHERE categories.name = ?{} AND categoryValue.value = ?{})", params.len() - 1, params.len())
+            }
+            Expr::Name(pattern) => {
+                params.push(glob_to_like(pattern));
+                format!("files.name LIKE ?{} ESCAPE '\\'", params.len())
+            }
+            Expr::Date(from, to) => {
+                let mut conditions = vec!["files.date IS NOT NULL".to_string()];
+                if let Some(from) = from {
+                    params.push(from.clone());
+                    conditions.push(format!("files.date >= ?{}", params.len()));
+                }
+                if let Some(to) = to {
+                    params.push(to.clone());
+                    conditions.push(format!("files.date <= ?{}", params.len()));
+                }
+                format!("({})", conditions.join(" AND "))
+            }
+            Expr::Not(expr) => format!("NOT ({})", expr.compile(params)),
+            Expr::And(exprs) => Expr::compile_all(exprs, " AND ", params),
+            Expr::Or(exprs) => Expr::compile_all(exprs, " OR ", params),
+        }
+    }
+
+    fn compile_all(exprs: &[Expr], operator: &str, params: &mut Vec<String>) -> String {
+        let conditions: Vec<String> = exprs.iter().map(|expr| expr.compile(params)).collect();
+        format!("({})", conditions.join(operator))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tag(t: &str) -> Expr {
+        Expr::Tag(t.to_string())
+    }
+
+    fn value(c: &str, v: &str) -> Expr {
+        Expr::Value(c.to_string(), v.to_string())
+    }
+
+    #[test]
+    fn parse_full_query() {
+        let expr = parse("tag:tax AND (Year=2023 OR Year=2024) AND NOT tag:draft name:*invoice*")
+            .expect("Parsing failed");
+        assert_eq!(
+            Expr::And(vec![
+                tag("tax"),
+                Expr::Or(vec![value("Year", "2023"), value("Year", "2024")]),
+                Expr::Not(Box::new(tag("draft"))),
+                Expr::Name("*invoice*".to_string())
+            ]),
+            expr
+        );
+    }
+
+    #[test]
+    fn parse_precedence() {
+        let expr = parse("tag:a OR tag:b tag:c").expect("Parsing failed");
+        assert_eq!(
+            Expr::Or(vec![tag("a"), Expr::And(vec![tag("b"), tag("c")])]),
+            expr
+        );
+    }
+
+    #[test]
+    fn parse_quoted() {
+        let expr = parse("person=\"Olivia Smith\" tag:\"a \\\"b\\\"\"").expect("Parsing failed");
+        assert_eq!(
+            Expr::And(vec![value("person", "Olivia Smith"), tag("a \"b\"")]),
+            expr
+        );
+    }
+
+    #[test]
+    fn parse_errors() {
+        let err = parse("tag:a AND (tag:b").unwrap_err();
+        assert_eq!(16, err.position);
+        assert_eq!("Expected ')', found end of query", err.message);
+
+        let err = parse("tag:a ) tag:b").unwrap_err();
+        assert_eq!(6, err.position);
+
+        let err = parse("foo:bar").unwrap_err();
+        assert_eq!(0, err.position);
+
+        let err = parse("tag: AND").unwrap_err();
+        assert_eq!(5, err.position);
+        assert_eq!(
+            "Expected a value, found 'AND' at position 5",
+            err.to_string()
+        );
+
+        let err = parse("tag:\"open").unwrap_err();
+        assert_eq!(4, err.position);
+
+        let err = parse("").unwrap_err();
+        assert_eq!(0, err.position);
+
+        let deep = format!("{}tag:a{}", "(".repeat(10_000), ")".repeat(10_000));
+        let err = parse(&deep).unwrap_err();
+        assert_eq!(MAX_DEPTH + 1, err.position);
+        let err = parse(&"NOT ".repeat(10_000)).unwrap_err();
+        assert_eq!(4 * (MAX_DEPTH + 1), err.position);
+        assert!(parse(&format!("{}tag:a", "NOT ".repeat(MAX_DEPTH))).is_ok());
+    }
+
+    #[test]
+    fn long_flat_query() {
+        let expr = parse(&"tag:a ".repeat(20_000)).expect("Parsing failed");
+        let mut params = Vec::new();
+        expr.compile(&mut params);
+        assert_eq!(20_000, params.len());
+        assert!(parse(&"tag:a OR ".repeat(20_000)).is_err());
+        assert!(parse(&format!("{}tag:a", "tag:a OR ".repeat(20_000))).is_ok());
+    }
+
+    #[test]
+    fn compile_parameters() {
+        let expr = parse("NOT tag:draft name:100%_*").expect("Parsing failed");
+        let mut params = Vec::new();
+        let sql = expr.compile(&mut params);
+        assert_eq!(vec!["draft".to_string(), "100\\%\\_%".to_string()], params);
+        assert!(sql.starts_with("(NOT (files.id IN"));
+        assert!(sql.ends_with("files.name LIKE ?2 ESCAPE '\\')"));
+    }
+
+    #[test]
+    fn parse_dates() {
+        assert_eq!(
+            Expr::Date(
+                Some("2024-01-01".to_string()),
+                Some("2024-01-01".to_string())
+            ),
+            parse("date:2024-01-01").expect("Parsing failed")
+        );
+        assert_eq!(
+            Expr::Date(None, Some("2024-12-31".to_string())),
+            parse("date:..2024-12-31").expect("Parsing failed")
+        );
+
+        let err = parse("tag:a date:2024-02-30..").unwrap_err();
+        assert_eq!(11, err.position);
+        assert_eq!(
+            "Invalid date '2024-02-30', expected YYYY-MM-DD",
+            err.message
+        );
+    }
+}
@@ -0,0 +1,325 @@
+use crate::config::{Config, Rule};
+use crate::db::FileMetadata;
+use crate::error::Error;
+use crate::extract;
+use log::debug;
+use regex::Regex;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// The tags and category values the rules assign to a file.
+#[derive(Serialize, Debug, Default, Clone, PartialEq)]
+pub struct RuleMatch {
+    /// Names of the matching rules
+    pub rules: Vec<String>,
+    pub tags: Vec<String>,
+    pub categories: HashMap<String, Vec<String>>,
+}
+
+impl RuleMatch {
+    /// Adds the matched tags and values to the metadata given by the user.
+    /// Values chosen by the user take precedence over the rules.
+    pub fn apply(&self, metadata: &FileMetadata) -> FileMetadata {
+        let mut result = metadata.clone();
+        for tag in &self.tags {
+            if !result.tags.contains(tag) {
+                result.tags.push(tag.clone());
+            }
+        }
+        for (category, values) in &self.categories {
+            result
+                .categories
+                .entry(category.clone())
+                .or_insert_with(|| values.clone());
+        }
+        result
+    }
+}
+
+fn regex(rule: &Rule) -> Result<Option<Regex>, Error> {
+    match rule.filename {
+        Some(ref pattern) => match Regex::new(pattern) {
+            Ok(regex) => Ok(Some(regex)),
+            Err(err) => Err(Error::Invalid(format!(
+                "Invalid file name in rule {}: {err}",
+                rule.name
+            ))),
+        },
+        None => Ok(None),
+    }
+}
+
+fn matches(rule: &Rule, path: &Path, content: Option<&str>) -> Result<bool, Error> {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+
+    if let Some(regex) = regex(rule)? {
+        if !regex.is_match(&name) {
+            return Ok(false);
+        }
+    }
+    if let Some(ref extension) = rule.extension {
+        let expected = extension.trim_start_matches('.');
+        match path.extension().and_then(|e| e.to_str()) {
+            Some(extension) if extension.eq_ignore_ascii_case(expected) => (),
+            _ => return Ok(false),
+        }
+    }
+    if let Some(ref text) = rule.contains {
+        match content {
+            Some(content) if content.to_lowercase().contains(&text.to_lowercase()) => (),
+            _ => return Ok(false),
+        }
+    }
+    if let Some(ref folder) = rule.folder {
+        match path.parent() {
+            Some(parent) if parent.starts_with(folder) => (),
+            _ => return Ok(false),
+        }
+    }
+    Ok(true)
+}
+
+/// Evaluates the rules for a file at `path` with the extracted `content`. If
+/// several rules assign values to the same category, the first rule wins.
+pub fn evaluate(rules: &[Rule], path: &Path, content: Option<&str>) -> Result<RuleMatch, Error> {
+    let mut result = RuleMatch::default();
+    for rule in rules {
+        if !matches(rule, path, content)? {
+            continue;
+        }
+        debug!("Rule {} matches {:?}", rule.name, path);
+        result.rules.push(rule.name.clone());
+        for tag in &rule.tags {
+            if !result.tags.contains(tag) {
+                result.tags.push(tag.clone());
+            }
+        }
+        for (category, values) in &rule.categories {
+            result
+                .categories
+                .entry(category.clone())
+                .or_insert_with(|| values.clone());
+        }
+    }
+    Ok(result)
+}
+
+/// Shows what a file would receive from the rules if it was imported.
+pub fn preview(rules: &[Rule], path: &Path) -> Result<RuleMatch, Error> {
+    if !path.is_file() {
+        return Err(Error::Invalid(format!("No file {:?}", path)));
+    }
+    let content = extract::extract_text(path);
+    evaluate(rules, path, content.as_deref())
+}
+
+/// Checks that all rules are valid and only assign known tags and values.
+pub fn check(config: &Config) -> Result<(), Error> {
+    for rule in &config.rules {
+        regex(rule)?;
+        if let Some(tag) = rule
+            .tags
+            .iter()
+            .find(|tag| !config.tags.contains(tag) && !config.aliases.contains_key(*tag))
+        {
+            return Err(Error::Invalid(format!(
+                "Unknown tag {tag} in rule {}",
+                rule.name
+            )));
+        }
+        for (category, values) in &rule.categories {
+            let known = match config.categories.iter().find(|c| c.name == *category) {
+                Some(known) => known,
+                None => {
+                    return Err(Error::Invalid(format!(
+                        "Unknown category {category} in rule {}",
+                        rule.name
+                    )))
+                }
+            };
+            if let Some(value) = values.iter().find(|value| !known.values.contains(value)) {
+                return Err(Error::Invalid(format!(
+                    "Unknown value {value} of {category} in rule {}",
+                    rule.name
+                )));
+            }
+            if values.len() > 1 && !known.multiple {
+                return Err(Error::Invalid(format!(
+                    "Category {category} takes a single value in rule {}",
+                    rule.name
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules() -> Vec<Rule> {
+        serde_json::from_value(serde_json::json!([
+            {
+                "name": "invoices",
+                "filename": "^(?i)invoice-\\d+",
+                "extension": "PDF",
+                "tags": ["invoice"],
+                "categories": { "sender": "ACME" },
+            },
+            {
+                "name": "taxes",
+                "contains": "tax office",
+                "tags": ["taxes", "invoice"],
+                "categories": { "sender": "Tax office", "person": "Olivia" },
+            },
+            {
+                "name": "scans",
+                "folder": "/home/olivia/Scans",
+                "tags": ["scan"],
+            },
+        ]))
+        .expect("Invalid rules")
+    }
+
+    #[test]
+    fn evaluate_conditions() {
+        let rules = rules();
+        let result = evaluate(&rules, Path::new("/tmp/Invoice-2024.pdf"), None).unwrap();
+        assert_eq!(vec!["invoices".to_string()], result.rules);
+        assert_eq!(vec!["invoice".to_string()], result.tags);
+
+        // All conditions of a rule have to match
+        let result = evaluate(&rules, Path::new("/tmp/invoice-2024.txt"), None).unwrap();
+        assert!(result.rules.is_empty());
+
+        let result = evaluate(
+            &rules,
+            Path::new("/home/olivia/Scans/2024/letter.txt"),
+            Some("Letter from the Tax Office"),
+        )
+        .unwrap();
+        assert_eq!(vec!["taxes".to_string(), "scans".to_string()], result.rules);
+        assert_eq!(vec!["taxes", "invoice", "scan"], result.tags);
+        assert_eq!(
+            Some(&vec!["Olivia".to_string()]),
+            result.categories.get("person")
+        );
+
+        let result = evaluate(&rules, Path::new("/home/olivia/Scans.pdf"), None).unwrap();
+        assert!(result.rules.is_empty());
+    }
+
+    #[test]
+    fn first_rule_wins() {
+        let result = evaluate(
+            &rules(),
+            Path::new("/tmp/invoice-1.pdf"),
+            Some("tax office"),
+        )
+        .unwrap();
+        assert_eq!(
+            Some(&vec!["ACME".to_string()]),
+            result.categories.get("sender")
+        );
+    }
+
+    #[test]
+    fn several_values() {
+        let rules: Vec<Rule> = serde_json::from_value(serde_json::json!([
+            { "name": "family", "categories": { "person": ["Olivia", "Liam"] } },
+        ]))
+        .expect("Invalid rules");
+        let result = evaluate(&rules, Path::new("/tmp/letter.txt"), None).unwrap();
+        assert_eq!(
+            Some(&vec!["Olivia".to_string(), "Liam".to_string()]),
+            result.categories.get("person")
+        );
+    }
+
+    #[test]
+    fn user_values_win() {
+        let result = evaluate(&rules(), Path::new("/tmp/letter.txt"), Some("tax office")).unwrap();
+        let metadata = result.apply(&FileMetadata {
+            tags: vec!["taxes".to_string(), "draft".to_string()],
+            categories: HashMap::from([("person".to_string(), vec!["Liam".to_string()])]),
+            date: None,
+        });
+        assert_eq!(vec!["taxes", "draft", "invoice"], metadata.tags);
+        assert_eq!(
+            Some(&vec!["Liam".to_string()]),
+            metadata.categories.get("person")
+        );
+        assert_eq!(
+            Some(&vec!["Tax office".to_string()]),
+            metadata.categories.get("sender")
+        );
+    }
+
+    #[test]
+    fn check_rules() {
+        let mut config: Config = serde_json::from_value(serde_json::json!({
+            "folder": "/tmp",
+            "save_date": false,
+            "categories": [
+                { "name": "person", "values": ["Olivia", "Liam"] },
+                { "name": "sender", "values": ["ACME", "Tax office"] },
+            ],
+            "tags": ["invoice", "taxes", "scan"],
+        }))
+        .expect("Invalid config");
+        config.rules = rules();
+        assert_eq!(Ok(()), check(&config));
+
+        config.rules[0].filename = Some("(".to_string());
+        assert!(check(&config).is_err());
+        config.rules = rules();
+        config.rules[1].tags.push("unknown".to_string());
+        assert!(check(&config).is_err());
+        config.rules = rules();
+        config.rules[1]
+            .categories
+            .insert("person".to_string(), vec!["Emma".to_string()]);
+        assert!(check(&config).is_err());
+        config.rules = rules();
+        config.rules[1].categories.insert(
+            "sender".to_string(),
+            vec!["ACME".to_string(), "Tax office".to_string()],
+        );
+        assert!(check(&config).is_err());
+        config.categories[1].multiple = true;
+        assert_eq!(Ok(()), check(&config));
+    }
+
+    #[test]
+    fn renames_update_rules() {
+        let mut config: Config = serde_json::from_value(serde_json::json!({
+            "folder": "/tmp",
+            "save_date": false,
+            "categories": [{ "name": "sender", "values": ["ACME", "Tax office"] }],
+            "tags": ["invoice", "taxes", "scan"],
+        }))
+        .expect("Invalid config");
+        config.rules = rules();
+        config.rename_tag(&"invoice".to_string(), "bill").unwrap();
+        config
+            .rename_category_value(
+                &"sender".to_string(),
+                &"ACME".to_string(),
+                &"Acme".to_string(),
+            )
+            .unwrap();
+        config
+            .rename_category(&"sender".to_string(), &"from".to_string())
+            .unwrap();
+        assert_eq!(vec!["bill"], config.rules[0].tags);
+        assert_eq!(
+            HashMap::from([("from".to_string(), vec!["Acme".to_string()])]),
+            config.rules[0].categories
+        );
+    }
+}
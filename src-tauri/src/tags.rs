@@ -0,0 +1,121 @@
+//! Tags form a tree through their names: `finance/tax/2024` is a child of
+//! `finance/tax`, which is a child of `finance`.
+
+use crate::error::Error;
+
+pub const SEPARATOR: char = '/';
+
+/// A tag needs a name for every level, like `finance/tax`, not `finance/`.
+pub fn check(tag: &str) -> Result<(), Error> {
+    if tag.split(SEPARATOR).any(|name| name.trim().is_empty()) {
+        return Err(Error::Invalid(format!(
+            "Invalid tag {tag:?}, every level needs a name"
+        )));
+    }
+    Ok(())
+}
+
+/// The ancestors of the tag, starting at the root.
+pub fn ancestors(tag: &str) -> Vec<&str> {
+    tag.match_indices(SEPARATOR)
+        .map(|(index, _)| &tag[..index])
+        .collect()
+}
+
+/// Whether `tag` is `ancestor` itself or one of its descendants.
+pub fn is_within(tag: &str, ancestor: &str) -> bool {
+    match tag.strip_prefix(ancestor) {
+        Some(rest) => rest.is_empty() || rest.starts_with(SEPARATOR),
+        None => false,
+    }
+}
+
+/// The name of `tag` after `old` was renamed to `new`, if it is affected.
+pub fn renamed(tag: &str, old: &str, new: &str) -> Option<String> {
+    if is_within(tag, old) {
+        Some(format!("{new}{}", &tag[old.len()..]))
+    } else {
+        None
+    }
+}
+
+/// The tags with all their ancestors, each ancestor before its descendants.
+pub fn with_ancestors(tags: &[String]) -> Vec<String> {
+    let mut result: Vec<String> = Vec::new();
+    for tag in tags {
+        for name in ancestors(tag).into_iter().chain([tag.as_str()]) {
+            if !result.iter().any(|t| t == name) {
+                result.push(name.to_string());
+            }
+        }
+    }
+    result
+}
+
+/// Sorts the tags like a tree, children right after their parent.
+pub fn sort(tags: &mut [String]) {
+    tags.sort_by(|a, b| a.split(SEPARATOR).cmp(b.split(SEPARATOR)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths() {
+        assert!(check("finance/tax/2024").is_ok());
+        for tag in ["", "finance/", "/tax", "finance//2024", "a/ "] {
+            assert!(matches!(check(tag), Err(Error::Invalid(_))), "{tag}");
+        }
+        assert_eq!(
+            vec!["finance", "finance/tax"],
+            ancestors("finance/tax/2024")
+        );
+        assert!(is_within("finance/tax", "finance"));
+        assert!(is_within("finance", "finance"));
+        assert!(!is_within("finances", "finance"));
+    }
+
+    #[test]
+    fn rename_subtree() {
+        assert_eq!(
+            Some("money/tax/2024".to_string()),
+            renamed("finance/tax/2024", "finance", "money")
+        );
+        assert_eq!(
+            Some("tax".to_string()),
+            renamed("finance/tax", "finance/tax", "tax")
+        );
+        assert_eq!(None, renamed("financial", "finance", "money"));
+    }
+
+    #[test]
+    fn tree_order() {
+        let mut tags = with_ancestors(&[
+            "finance/tax/2024".to_string(),
+            "finance-old".to_string(),
+            "finance/insurance".to_string(),
+        ]);
+        assert_eq!(
+            vec![
+                "finance",
+                "finance/tax",
+                "finance/tax/2024",
+                "finance-old",
+                "finance/insurance"
+            ],
+            tags
+        );
+        sort(&mut tags);
+        assert_eq!(
+            vec![
+                "finance",
+                "finance/insurance",
+                "finance/tax",
+                "finance/tax/2024",
+                "finance-old"
+            ],
+            tags
+        );
+    }
+}
@@ -0,0 +1,72 @@
+//! Fixtures shared by the tests of all modules.
+
+use crate::config::Config;
+use crate::db::Database;
+use std::fs;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+/// An empty folder in the temp dir, removed again when dropped.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> TempDir {
+        let dir = std::env::temp_dir().join(format!("files-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).expect("Failed to create folder");
+        TempDir(dir)
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// A vault in the `vault` folder of `root`, with its database in line with
+/// the config.
+pub struct TestVault {
+    pub root: TempDir,
+    pub vault: PathBuf,
+    pub config: Config,
+    pub db: Database,
+}
+
+impl TestVault {
+    /// `settings` is the config without the folder, e.g. the tags and
+    /// categories.
+    pub fn new(name: &str, mut settings: serde_json::Value) -> TestVault {
+        let root = TempDir::new(name);
+        let vault = root.join("vault");
+        fs::create_dir_all(&vault).expect("Failed to create vault");
+
+        settings["folder"] = serde_json::json!(vault);
+        let config: Config = serde_json::from_value(settings).expect("Invalid config");
+        let mut db = Database::new();
+        db.open(config.db_path()).expect("Failed to open database");
+        db.check_config_consistency(&config)
+            .expect("Failed to apply config");
+
+        TestVault {
+            root,
+            vault,
+            config,
+            db,
+        }
+    }
+}
@@ -0,0 +1,147 @@
+use crate::error::Error;
+use crate::vault;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+/// How an imported file gets into the vault.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportMode {
+    /// The original is left untouched
+    #[default]
+    Copy,
+    /// The original is deleted once the file is stored in the vault
+    Move,
+    /// The vault shares the content with the original, so changes to one
+    /// affect both. Requires the vault to be on the same file system.
+    Hardlink,
+    /// A copy-on-write clone, where the file system supports it, otherwise a
+    /// normal copy
+    Reflink,
+}
+
+/// Copies `source` to `target` and checks that the copy has the expected
+/// content.
+fn copy_verified(source: &Path, target: &Path, hash: &str) -> Result<(), Error> {
+    let bytes = fs::copy(source, target)
+        .map_err(|err| Error::from(err).context("Importing file failed"))?;
+    debug!("Copied {bytes} to {:?}", target);
+    if vault::hash_file(target)? != hash {
+        return Err(Error::Io(format!(
+            "Importing file failed: the copy of {:?} differs from the original",
+            source
+        )));
+    }
+    Ok(())
+}
+
+/// Puts the file at `source` into the vault at `target`. The target may be an
+/// empty placeholder reserved by the layout. `hash` is the hash of the source.
+/// The original of a move is only deleted by `finish`, after the import is
+/// stored.
+pub fn transfer(mode: ImportMode, source: &Path, target: &Path, hash: &str) -> Result<(), Error> {
+    // Links can not replace an existing file
+    if mode != ImportMode::Copy && target.exists() {
+        fs::remove_file(target).map_err(|err| Error::from(err).context("Importing file failed"))?;
+    }
+    match mode {
+        ImportMode::Copy => {
+            let bytes = fs::copy(source, target)
+                .map_err(|err| Error::from(err).context("Importing file failed"))?;
+            debug!("Copied {bytes} to {:?}", target);
+            Ok(())
+        }
+        // A hard link is enough for a move on the same file system, across
+        // file systems the copy is verified before the original is deleted
+        ImportMode::Move => match fs::hard_link(source, target) {
+            Ok(()) => Ok(()),
+            Err(err) => {
+                debug!("Linking {:?} failed, copying instead: {err}", source);
+                copy_verified(source, target, hash)
+            }
+        },
+        ImportMode::Hardlink => fs::hard_link(source, target)
+            .map_err(|err| Error::from(err).context("Linking file into the vault failed")),
+        ImportMode::Reflink => match reflink_copy::reflink_or_copy(source, target) {
+            Ok(None) => {
+                debug!("Cloned {:?}", source);
+                Ok(())
+            }
+            Ok(Some(bytes)) => {
+                debug!("Reflinks are not supported, copied {bytes} to {:?}", target);
+                Ok(())
+            }
+            Err(err) => Err(Error::from(err).context("Importing file failed")),
+        },
+    }
+}
+
+/// Completes a transfer once the file is stored in the database.
+pub fn finish(mode: ImportMode, source: &Path) {
+    if mode == ImportMode::Move {
+        match fs::remove_file(source) {
+            Ok(()) => debug!("Removed original {:?}", source),
+            Err(err) => warn!("Removing the original {:?} failed: {err}", source),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    fn create_dir(name: &str) -> TempDir {
+        let dir = TempDir::new(name);
+        fs::write(dir.join("letter.txt"), "Dear Olivia").unwrap();
+        dir
+    }
+
+    #[test]
+    fn transfer_modes() {
+        let dir = create_dir("transfer");
+        let source = dir.join("letter.txt");
+        let hash = vault::hash_file(&source).unwrap();
+
+        for mode in [
+            ImportMode::Copy,
+            ImportMode::Hardlink,
+            ImportMode::Reflink,
+            ImportMode::Move,
+        ] {
+            let target = dir.join(format!("{mode:?}.txt"));
+            // Placeholder reserved by the layout
+            fs::write(&target, "").unwrap();
+            transfer(mode, &source, &target, &hash).expect("Transfer failed");
+            assert_eq!("Dear Olivia", fs::read_to_string(&target).unwrap());
+            assert!(source.exists());
+        }
+
+        // Changes to the original show up in a hard link
+        fs::write(&source, "Dear Liam").unwrap();
+        assert_eq!(
+            "Dear Liam",
+            fs::read_to_string(dir.join("Hardlink.txt")).unwrap()
+        );
+        assert_eq!(
+            "Dear Olivia",
+            fs::read_to_string(dir.join("Copy.txt")).unwrap()
+        );
+
+        finish(ImportMode::Copy, &source);
+        assert!(source.exists());
+        finish(ImportMode::Move, &source);
+        assert!(!source.exists());
+    }
+
+    #[test]
+    fn verify_copy() {
+        let dir = create_dir("transfer-verify");
+        let source = dir.join("letter.txt");
+        let hash = vault::hash_file(&source).unwrap();
+        assert_eq!(Ok(()), copy_verified(&source, &dir.join("copy.txt"), &hash));
+        assert!(copy_verified(&source, &dir.join("other.txt"), "0000").is_err());
+    }
+}
@@ -0,0 +1,392 @@
+use crate::config::Config;
+use crate::db::{Database, FileEntry, FileMetadata, SearchQuery};
+use crate::error::Error;
+use crate::extract;
+use crate::layout::LayoutKind;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+/// A difference between the database and the files in the vault.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Problem {
+    /// A file in the database that is gone from the vault. Repaired by
+    /// removing its entry.
+    Missing { path: String, location: PathBuf },
+    /// A file or folder in the vault without an entry. Repaired by registering
+    /// it as a new file without metadata.
+    Orphan { location: PathBuf },
+    /// The content changed since the import. Repaired by storing the new hash.
+    HashMismatch {
+        path: String,
+        expected: String,
+        actual: String,
+    },
+    /// Tag and value assignments without a file, tag or value. Repaired by
+    /// deleting them.
+    Dangling { tags: usize, values: usize },
+}
+
+/// The result of `verify`.
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct Report {
+    /// Number of files in the database
+    pub files: usize,
+    pub problems: Vec<Problem>,
+}
+
+/// Opens the database of the vault and takes the tags and categories from it,
+/// so every machine opening the vault sees the same ones. Tags and categories
+/// still in the config, from before the vault stored them, are added first.
+pub fn open(config: &mut Config) -> Result<Database, Error> {
+    let mut db = Database::new();
+    db.open(config.db_path())?;
+    if config.has_taxonomy() {
+        info!("Moving tags and categories of the config into the vault");
+        db.import_taxonomy(config)?;
+    }
+    db.load_taxonomy(config)?;
+    Ok(db)
+}
+
+/// The SHA-256 hash of the file content as hex string.
+pub fn hash_file(path: &Path) -> Result<String, Error> {
+    let mut file = File::open(path)
+        .map_err(|err| Error::from(err).context(&format!("Reading {:?} failed", path)))?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)
+        .map_err(|err| Error::from(err).context(&format!("Hashing {:?} failed", path)))?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Stores the hashes of files imported before hashes were introduced. Files
+/// missing in the vault are skipped.
+pub fn hash_missing(db: &Database, vault: &Path) -> Result<usize, Error> {
+    let mut hashed = 0;
+    for file in db.files_without_hash()? {
+        match hash_file(&file.location(vault)) {
+            Ok(hash) => {
+                db.set_file_hash(&file.path, &hash)?;
+                hashed += 1;
+            }
+            Err(err) => warn!("{err}"),
+        }
+    }
+    if hashed > 0 {
+        info!("Stored the hashes of {hashed} files");
+    }
+    Ok(hashed)
+}
+
+/// Groups of files in the vault with the same content.
+pub fn find_duplicates(db: &Database, vault: &Path) -> Result<Vec<Vec<FileEntry>>, Error> {
+    hash_missing(db, vault)?;
+    db.find_duplicates()
+}
+
+/// Whether an entry of the vault folder belongs to the app rather than to a
+/// file, like the database or the hidden leftovers of a deletion.
+fn is_internal(name: &str) -> bool {
+    name.starts_with('.') || name.starts_with("files.sqlite")
+}
+
+/// Walks the vault and collects everything not belonging to a known file.
+fn find_orphans(
+    vault: &Path,
+    folder: &Path,
+    known: &HashSet<PathBuf>,
+    parents: &HashSet<PathBuf>,
+    orphans: &mut Vec<PathBuf>,
+) -> Result<(), Error> {
+    let entries = fs::read_dir(folder)
+        .map_err(|err| Error::from(err).context(&format!("Reading {:?} failed", folder)))?;
+    let mut paths = Vec::new();
+    for entry in entries {
+        let entry = entry
+            .map_err(|err| Error::from(err).context(&format!("Reading {:?} failed", folder)))?;
+        if !is_internal(&entry.file_name().to_string_lossy()) {
+            paths.push(entry.path());
+        }
+    }
+    paths.sort();
+    for path in paths {
+        let relative = path.strip_prefix(vault).unwrap_or(&path).to_path_buf();
+        if known.contains(&relative) {
+            continue;
+        }
+        if parents.contains(&relative) && path.is_dir() {
+            find_orphans(vault, &path, known, parents, orphans)?;
+        } else {
+            orphans.push(relative);
+        }
+    }
+    Ok(())
+}
+
+/// Compares the database with the vault. Hashing every file takes a while, so
+/// hashes are only compared with `check_hashes`.
+pub fn verify(db: &Database, vault: &Path, check_hashes: bool) -> Result<Report, Error> {
+    if !vault.is_dir() {
+        return Err(Error::Io(format!("No vault at {:?}", vault)));
+    }
+    let files = db.search(&SearchQuery::default())?;
+    let hashes = match check_hashes {
+        true => db.file_hashes()?,
+        false => Default::default(),
+    };
+    let mut report = Report {
+        files: files.len(),
+        problems: Vec::new(),
+    };
+
+    // Files in their own folder are known by the folder, others by the file
+    let mut known = HashSet::new();
+    let mut parents = HashSet::new();
+    for file in &files {
+        let location = file.relative_location();
+        let stored = PathBuf::from(&file.path);
+        parents.extend(stored.ancestors().skip(1).map(Path::to_path_buf));
+        known.insert(stored);
+
+        if !vault.join(&location).is_file() {
+            report.problems.push(Problem::Missing {
+                path: file.path.clone(),
+                location,
+            });
+            continue;
+        }
+        if let Some(expected) = hashes.get(&file.path) {
+            let actual = hash_file(&vault.join(&location))?;
+            if actual != *expected {
+                report.problems.push(Problem::HashMismatch {
+                    path: file.path.clone(),
+                    expected: expected.clone(),
+                    actual,
+                });
+            }
+        }
+    }
+
+    let mut orphans = Vec::new();
+    find_orphans(vault, vault, &known, &parents, &mut orphans)?;
+    report.problems.extend(
+        orphans
+            .into_iter()
+            .map(|location| Problem::Orphan { location }),
+    );
+
+    let (tags, values) = db.count_dangling()?;
+    if tags + values > 0 {
+        report.problems.push(Problem::Dangling { tags, values });
+    }
+    if !report.problems.is_empty() {
+        warn!("Vault check found {} problems", report.problems.len());
+    }
+    Ok(report)
+}
+
+/// Registers an orphan without metadata. A folder with a single file becomes
+/// a file stored in its own folder, a single file is stored where it is.
+fn register(db: &Database, vault: &Path, location: &Path) -> Result<(), Error> {
+    if !location
+        .components()
+        .all(|c| matches!(c, Component::Normal(_)))
+    {
+        return Err(Error::Invalid(format!(
+            "Registering {:?} failed: invalid location",
+            location
+        )));
+    }
+    let path = vault.join(location);
+    let (file, layout) = if path.is_dir() {
+        let files: Vec<PathBuf> = fs::read_dir(&path)
+            .map_err(|err| Error::from(err).context(&format!("Reading {:?} failed", path)))?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .collect();
+        match files.as_slice() {
+            [file] if file.is_file() => (file.clone(), LayoutKind::Random),
+            _ => {
+                return Err(Error::Conflict(format!(
+                    "Registering {:?} failed: the folder has to contain a single file",
+                    location
+                )))
+            }
+        }
+    } else {
+        (path.clone(), LayoutKind::Template)
+    };
+    let (relative, name) = match (
+        location.to_str(),
+        file.file_name().and_then(|name| name.to_str()),
+    ) {
+        (Some(relative), Some(name)) => (relative.to_string(), name.to_string()),
+        _ => {
+            return Err(Error::Invalid(format!(
+                "Registering {:?} failed: invalid name",
+                location
+            )))
+        }
+    };
+
+    let hash = hash_file(&file)?;
+    let content = extract::extract_text(&file);
+    db.store_import(
+        &relative,
+        &name,
+        layout,
+        &FileMetadata::default(),
+        content.as_ref(),
+        Some(&hash),
+    )?;
+    info!("Registered {relative}");
+    Ok(())
+}
+
+/// Applies the repair for a problem found by `verify`.
+pub fn repair(db: &Database, vault: &Path, problem: &Problem) -> Result<(), Error> {
+    match problem {
+        Problem::Missing { path, .. } => db.forget_file(path),
+        Problem::Orphan { location } => register(db, vault, location),
+        Problem::HashMismatch { path, actual, .. } => db.set_file_hash(path, actual),
+        Problem::Dangling { .. } => db.remove_dangling().map(|_| ()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::import;
+    use crate::test_util::{TempDir, TestVault};
+
+    #[test]
+    fn hash_content() {
+        let dir = TempDir::new("hash");
+        let path = dir.join("letter.txt");
+        fs::write(&path, "abc").unwrap();
+        assert_eq!(
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+            hash_file(&path).unwrap()
+        );
+        assert!(hash_file(&dir.join("missing.txt")).is_err());
+    }
+
+    #[test]
+    fn taxonomy_in_vault() {
+        let vault = TempDir::new("taxonomy");
+        let mut legacy: Config = serde_json::from_value(serde_json::json!({
+            "folder": *vault,
+            "save_date": false,
+            "categories": [{"name": "person", "values": ["Olivia"]}],
+            "tags": ["taxes"],
+        }))
+        .expect("Invalid config");
+        open(&mut legacy).expect("Opening the vault failed");
+
+        // Another machine only knows where the vault is
+        let mut config: Config = serde_json::from_value(serde_json::json!({
+            "folder": *vault,
+            "save_date": false,
+        }))
+        .expect("Invalid config");
+        open(&mut config).expect("Opening the vault failed");
+        assert_eq!(vec!["taxes"], config.tags);
+        assert_eq!(legacy.categories, config.categories);
+    }
+
+    #[test]
+    fn verify_and_repair() {
+        let TestVault {
+            root,
+            vault,
+            config,
+            db,
+        } = TestVault::new(
+            "verify",
+            serde_json::json!({
+                "save_date": false,
+                "categories": [],
+                "tags": ["taxes"],
+            }),
+        );
+
+        let mut stored = Vec::new();
+        for (name, content) in [("a.txt", "A"), ("b.txt", "B"), ("c.txt", "C")] {
+            let path = root.join(name);
+            fs::write(&path, content).unwrap();
+            let metadata = FileMetadata {
+                tags: vec!["taxes".to_string()],
+                ..Default::default()
+            };
+            stored.push(import::import_file(&db, &config, &path, &metadata, false).unwrap());
+        }
+        assert_eq!(
+            Vec::<Problem>::new(),
+            verify(&db, &vault, true).unwrap().problems
+        );
+
+        // A sync tool removed a folder, changed a file and left others behind
+        fs::remove_dir_all(vault.join(&stored[0])).unwrap();
+        fs::write(vault.join(&stored[1]).join("b.txt"), "Changed").unwrap();
+        fs::create_dir_all(vault.join("lost")).unwrap();
+        fs::write(vault.join("lost/d.txt"), "D").unwrap();
+        fs::write(vault.join("e.txt"), "E").unwrap();
+        fs::write(vault.join(".stfolder"), "").unwrap();
+        rusqlite::Connection::open(config.db_path())
+            .unwrap()
+            .execute_batch(
+                "PRAGMA foreign_keys = OFF; INSERT INTO fileTags(file_id, tag_id) VALUES (99, 1)",
+            )
+            .unwrap();
+
+        let report = verify(&db, &vault, true).unwrap();
+        assert_eq!(3, report.files);
+        assert_eq!(
+            Problem::Missing {
+                path: stored[0].clone(),
+                location: Path::new(&stored[0]).join("a.txt"),
+            },
+            report.problems[0]
+        );
+        assert!(matches!(
+            report.problems[1],
+            Problem::HashMismatch { ref path, .. } if *path == stored[1]
+        ));
+        assert_eq!(
+            vec![
+                Problem::Orphan {
+                    location: PathBuf::from("e.txt")
+                },
+                Problem::Orphan {
+                    location: PathBuf::from("lost")
+                },
+                Problem::Dangling { tags: 1, values: 0 },
+            ],
+            report.problems[2..]
+        );
+        // Without hashes, changed files are not noticed
+        assert_eq!(4, verify(&db, &vault, false).unwrap().problems.len());
+
+        for problem in &report.problems {
+            repair(&db, &vault, problem).expect("Repair failed");
+        }
+        assert_eq!(
+            Vec::<Problem>::new(),
+            verify(&db, &vault, true).unwrap().problems
+        );
+        let lost = db.get_file(&"lost".to_string()).unwrap();
+        assert_eq!(vault.join("lost/d.txt"), lost.location(&vault));
+        let file = db.get_file(&"e.txt".to_string()).unwrap();
+        assert_eq!(vault.join("e.txt"), file.location(&vault));
+        assert!(db.get_file(&stored[0]).is_err());
+
+        let outside = Problem::Orphan {
+            location: PathBuf::from("../a.txt"),
+        };
+        assert!(repair(&db, &vault, &outside).is_err());
+    }
+}
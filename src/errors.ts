@@ -0,0 +1,18 @@
+// Commands fail with an object like { code: "unknown_tag", message: "..." },
+// see src-tauri/src/error.rs for the codes
+export interface CommandError {
+  code: string;
+  message: string;
+}
+
+export function isCommandError(err: unknown): err is CommandError {
+  return typeof err === "object" && err !== null && "code" in err;
+}
+
+export function errorCode(err: unknown): string {
+  return isCommandError(err) ? err.code : "";
+}
+
+export function errorMessage(err: unknown): string {
+  return isCommandError(err) ? err.message : String(err);
+}